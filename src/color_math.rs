@@ -0,0 +1,142 @@
+use crate::output::{ScanResult, Triple};
+
+/// Whether L*, a* or b* strictly increases or strictly decreases across the whole sequence
+pub fn is_monotone_gradient(results: &[ScanResult]) -> bool {
+	if results.len() < 2 { return false; }
+	(0..3).any(|c| {
+		let values: Vec<f32> = results.iter().map(|r| r.lab.0[c]).collect();
+		values.windows(2).all(|w| w[0] < w[1]) || values.windows(2).all(|w| w[0] > w[1])
+	})
+}
+
+/// XYZ (Y of white = 1.0) from the device's yxY triple (Y, x and y all in percent)
+pub fn yxy_to_xyz(yxy: &Triple<f32>) -> Triple<f32> {
+	let [big_y, x, y] = yxy.map(|n| n / 100.0).0;
+	if y == 0.0 { return Triple([0.0; 3]); }
+	Triple([x * big_y / y, big_y, (1.0 - x - y) * big_y / y])
+}
+
+/// Slack for the rounding of the device's two-decimal values
+const GAMUT_EPSILON: f32 = 0.002;
+
+/// Whether linear RGB from this XYZ-to-RGB matrix stays within 0..1
+fn in_gamut(matrix: &[[f32; 3]; 3], xyz: &Triple<f32>) -> bool {
+	matrix.iter().all(|row| {
+		let c: f32 = row.iter().zip(xyz.0).map(|(m, v)| m * v).sum();
+		(-GAMUT_EPSILON..=1.0 + GAMUT_EPSILON).contains(&c)
+	})
+}
+
+/// XYZ (D65) to linear sRGB
+const SRGB_MATRIX: [[f32; 3]; 3] = [
+	[3.240454, -1.537138, -0.498531],
+	[-0.969266, 1.876011, 0.041556],
+	[0.055643, -0.204026, 1.057225],
+];
+
+/// XYZ (D65) to linear Display P3
+const DISPLAY_P3_MATRIX: [[f32; 3]; 3] = [
+	[2.493497, -0.931384, -0.402711],
+	[-0.829489, 1.762664, 0.023625],
+	[0.035846, -0.076172, 0.956885],
+];
+
+/// XYZ (D65) to linear Adobe RGB (1998)
+const ADOBE_RGB_MATRIX: [[f32; 3]; 3] = [
+	[2.041369, -0.564946, -0.344694],
+	[-0.969266, 1.876011, 0.041556],
+	[0.013447, -0.11839, 1.01541],
+];
+
+pub fn in_srgb(xyz: &Triple<f32>) -> bool {
+	in_gamut(&SRGB_MATRIX, xyz)
+}
+
+pub fn in_display_p3(xyz: &Triple<f32>) -> bool {
+	in_gamut(&DISPLAY_P3_MATRIX, xyz)
+}
+
+pub fn in_adobe_rgb(xyz: &Triple<f32>) -> bool {
+	in_gamut(&ADOBE_RGB_MATRIX, xyz)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::testing::scan_result;
+	use super::*;
+	
+	fn scans(labs: &[[f32; 3]]) -> Vec<ScanResult> {
+		labs.iter().enumerate().map(|(i, &lab)| ScanResult { lab: Triple(lab), ..scan_result(i + 1) }).collect()
+	}
+	
+	#[test]
+	fn gradients() {
+		// Each of L, a and b on its own, both ways
+		assert!(is_monotone_gradient(&scans(&[[10.0, 0.0, 0.0], [20.0, 0.0, 0.0], [30.0, 0.0, 0.0]])));
+		assert!(is_monotone_gradient(&scans(&[[50.0, 5.0, 0.0], [50.0, -5.0, 0.0], [50.0, -15.0, 0.0]])));
+		assert!(is_monotone_gradient(&scans(&[[50.0, 0.0, -20.0], [50.0, 0.0, 0.0], [50.0, 0.0, 20.0]])));
+		// The others may go any way
+		assert!(is_monotone_gradient(&scans(&[[10.0, 3.0, -1.0], [20.0, -3.0, 4.0], [30.0, 3.0, 2.0]])));
+		
+		// Mixed in every component
+		assert!(!is_monotone_gradient(&scans(&[[10.0, 0.0, 0.0], [20.0, 5.0, 1.0], [15.0, 0.0, 0.0]])));
+		// Strictly: equal neighbours don't count
+		assert!(!is_monotone_gradient(&scans(&[[10.0, 0.0, 0.0], [10.0, 0.0, 0.0], [20.0, 0.0, 0.0]])));
+		
+		// Too few scans to tell
+		assert!(!is_monotone_gradient(&[]));
+		assert!(!is_monotone_gradient(&scans(&[[10.0, 0.0, 0.0]])));
+		assert!(is_monotone_gradient(&scans(&[[10.0, 0.0, 0.0], [11.0, 0.0, 0.0]])));
+	}
+	
+	/// Linear sRGB to XYZ (D65), the inverse of [SRGB_MATRIX]
+	const SRGB_TO_XYZ: [[f32; 3]; 3] = [
+		[0.4124564, 0.3575761, 0.1804375],
+		[0.2126729, 0.7151522, 0.072175],
+		[0.0193339, 0.119192, 0.9503041],
+	];
+	
+	fn apply(matrix: &[[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+		matrix.map(|row| row.iter().zip(v).map(|(m, v)| m * v).sum())
+	}
+	
+	/// The README's samples as (yxY, RGB)
+	const SAMPLES: [([f32; 3], [u8; 3]); 3] = [
+		([82.03, 31.44, 33.21], [234, 234, 231]),
+		([27.02, 34.2, 40.42], [135, 147, 103]),
+		([49.93, 33.69, 32.04], [213, 179, 186]),
+	];
+	
+	#[test]
+	fn samples_round_trip_through_srgb() {
+		let encode = |c: f32| if c <= 0.0031308 { 12.92 * c } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 };
+		for (yxy, rgb) in SAMPLES {
+			let xyz = yxy_to_xyz(&Triple(yxy));
+			assert!(in_srgb(&xyz) && in_display_p3(&xyz) && in_adobe_rgb(&xyz));
+			// The device's own RGB, give or take its rounding
+			let ours = apply(&SRGB_MATRIX, xyz.0).map(|c| encode(c) * 255.0);
+			for (ours, theirs) in ours.into_iter().zip(rgb) {
+				assert!((ours - theirs as f32).abs() < 1.0, "{ours} vs {theirs} for {yxy:?}");
+			}
+		}
+		assert_eq!(yxy_to_xyz(&Triple([50.0, 30.0, 0.0])), Triple([0.0; 3]));
+	}
+	
+	#[test]
+	fn gamuts() {
+		// A green only the wider gamuts reach
+		let green = yxy_to_xyz(&Triple([50.0, 28.0, 62.0]));
+		assert!(!in_srgb(&green));
+		assert!(in_display_p3(&green));
+		assert!(in_adobe_rgb(&green));
+		
+		// Just outside sRGB is let through as rounding, a little further isn't
+		let xyz = |rgb: [f32; 3]| Triple(apply(&SRGB_TO_XYZ, rgb));
+		assert!(in_srgb(&xyz([0.5, 0.5, 0.5])));
+		assert!(in_srgb(&xyz([0.5, 0.5, -0.001])));
+		assert!(in_srgb(&xyz([1.001, 0.5, 0.5])));
+		assert!(!in_srgb(&xyz([0.5, 0.5, -0.005])));
+		assert!(!in_srgb(&xyz([1.005, 0.5, 0.5])));
+	}
+	
+}
@@ -0,0 +1,1122 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::{Duration, Instant};
+
+use btleplug::api::{Characteristic, Peripheral as _};
+use btleplug::api::WriteType::WithoutResponse;
+use btleplug::platform::Peripheral;
+use byteorder::{ByteOrder, LittleEndian};
+use log::{debug, trace, warn};
+use tokio::sync::{mpsc, Notify};
+use tokio_stream::{Stream, StreamExt};
+use uuid::Uuid;
+use crate::output::ScanResult;
+use crate::protocol::{message_kind, parse_scan_result, split_scan_records, MessageKind, Reassembler, BATTERY_CMD, CALIBRATE_CMD, INFO_CMD, NOTIF_CHR_ID, SCAN_CMD, WRITE_CHR_ID};
+use crate::timing::TimingLog;
+
+/// How many times to run service discovery before giving up on the characteristics
+const DISCOVER_ATTEMPTS: usize = 3;
+/// Pause between service discovery attempts
+const DISCOVER_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Longest gap between fragments of one message
+const FRAGMENT_WINDOW: Duration = Duration::from_millis(500);
+
+/// Scans older than this don't count towards the scan rate
+const SCAN_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// How often [Event::Heartbeat] is sent
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Pause before retrying a failed write
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+pub type NotificationStream = Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>;
+
+/// The link to the device: somewhere to write commands and a stream of what comes back
+pub trait Transport: Send + Sync + 'static {
+	fn subscribe(&self) -> impl Future<Output = Result<(), anyhow::Error>> + Send;
+	fn notifications(&self) -> impl Future<Output = Result<NotificationStream, anyhow::Error>> + Send;
+	fn write(&self, data: &[u8]) -> impl Future<Output = Result<(), anyhow::Error>> + Send;
+	fn disconnect(&self) -> impl Future<Output = Result<(), anyhow::Error>> + Send;
+}
+
+/// A connected BLE peripheral
+pub struct BleTransport {
+	device: Peripheral,
+	notif_char: Characteristic,
+	/// Looked up again if a write fails
+	write_char: Mutex<Characteristic>,
+}
+
+/// The write characteristic was gone when looked up again after a failed write,
+/// which has been seen after firmware hiccups while notifications kept coming.
+/// Retrying won't help, so [device_loop] gives up on it right away.
+#[derive(Debug)]
+pub struct CharacteristicLost;
+
+impl std::fmt::Display for CharacteristicLost {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "Write characteristic lost")
+	}
+}
+
+impl std::error::Error for CharacteristicLost {}
+
+impl BleTransport {
+	
+	/// Connects if needed and finds the characteristics
+	pub async fn connect(device: Peripheral) -> Result<Self, anyhow::Error> {
+		
+		let connected = device.is_connected().await?;
+		debug!("Connected = {connected}");
+		if !connected {
+			debug!("Connecting");
+			device.connect().await?;
+		}
+		debug!("Connected");
+		
+		// Some platforms occasionally report an incomplete list on the first go
+		let mut attempt = 0;
+		let (notif_char, write_char) = loop {
+			attempt += 1;
+			debug!("Discovering services, attempt {attempt}");
+			device.discover_services().await?;
+			let chars = device.characteristics();
+			trace!("chars = {chars:?}");
+			let notif_char = chars.iter().find(|c| c.uuid == *NOTIF_CHR_ID).cloned();
+			let write_char = chars.iter().find(|c| c.uuid == *WRITE_CHR_ID).cloned();
+			if let (Some(notif_char), Some(write_char)) = (notif_char, write_char) {
+				break (notif_char, write_char);
+			}
+			if attempt >= DISCOVER_ATTEMPTS {
+				return Err(anyhow::Error::msg("Device is missing the expected characteristics"));
+			}
+			warn!("Expected characteristics not found, retrying discovery");
+			tokio::time::sleep(DISCOVER_RETRY_DELAY).await;
+		};
+		trace!("notif_char = {notif_char:?}");
+		trace!("write_char = {write_char:?}");
+		
+		Ok(Self { device, notif_char, write_char: Mutex::new(write_char) })
+	}
+	
+}
+
+impl Transport for BleTransport {
+	async fn subscribe(&self) -> Result<(), anyhow::Error> {
+		Ok(self.device.subscribe(&self.notif_char).await?)
+	}
+	async fn notifications(&self) -> Result<NotificationStream, anyhow::Error> {
+		Ok(Box::pin(self.device.notifications().await?.map(|v| v.value)))
+	}
+	/// Looks the write characteristic up again and retries once if the write fails
+	async fn write(&self, data: &[u8]) -> Result<(), anyhow::Error> {
+		let write_char = self.write_char.lock().unwrap().clone();
+		let Err(e) = self.device.write(&write_char, data, WithoutResponse).await else { return Ok(()) };
+		debug!("Write failed ({e}), rediscovering services");
+		self.device.discover_services().await?;
+		let Some(write_char) = self.device.characteristics().into_iter().find(|c| c.uuid == *WRITE_CHR_ID) else {
+			return Err(CharacteristicLost.into());
+		};
+		trace!("write_char = {write_char:?}");
+		*self.write_char.lock().unwrap() = write_char.clone();
+		Ok(self.device.write(&write_char, data, WithoutResponse).await?)
+	}
+	async fn disconnect(&self) -> Result<(), anyhow::Error> {
+		Ok(self.device.disconnect().await?)
+	}
+}
+
+/// Something to ask of the device
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+	/// Device info, then battery level
+	Status,
+	Calibrate,
+	Scan,
+	/// Scan and attach this label to the result
+	ScanLabeled(String),
+	/// A complete frame, checksum included
+	Raw(Vec<u8>),
+	/// Fetch the scans stored on the device, using this frame (checksum included) as the command,
+	/// since the real one is not known. The answer is split with [split_scan_records].
+	DownloadHistory(Vec<u8>),
+	Disconnect,
+}
+
+/// A frame to write, and what to make of its answer
+struct Frame {
+	data: Vec<u8>,
+	/// The kind of message that answers this, `None` for any AB message
+	expect: Option<MessageKind>,
+	/// The answer is a batch of stored scans
+	history: bool,
+	/// Attached to the scan that answers this
+	label: Option<String>,
+	/// Times left to send this again if it gets no answer
+	retries: usize,
+	/// When it was last written
+	sent_at: Option<Instant>,
+}
+
+impl Frame {
+	fn new(data: &[u8], expect: Option<MessageKind>) -> Self {
+		Self { data: data.to_vec(), expect, history: false, label: None, retries: 0, sent_at: None }
+	}
+	
+	/// Scan commands are the ones the device has been seen to drop
+	fn is_live_scan(&self) -> bool {
+		self.expect == Some(MessageKind::Scan) && !self.history
+	}
+	
+	fn answered_by(&self, kind: Option<MessageKind>) -> bool {
+		self.expect.is_none() || self.expect == kind
+	}
+}
+
+impl Command {
+	/// The frames to write, each of which is expected to get one answer
+	fn frames(&self) -> Vec<Frame> {
+		match self {
+			Command::Status => vec![
+				Frame::new(&INFO_CMD, Some(MessageKind::DeviceInfo)),
+				Frame::new(&BATTERY_CMD, Some(MessageKind::PowerLevel)),
+			],
+			Command::Calibrate => vec![Frame::new(&CALIBRATE_CMD, Some(MessageKind::Calibrated))],
+			Command::Scan => vec![Frame::new(&SCAN_CMD, Some(MessageKind::Scan))],
+			Command::ScanLabeled(label) => vec![Frame { label: Some(label.clone()), ..Frame::new(&SCAN_CMD, Some(MessageKind::Scan)) }],
+			// Nothing known about these, so anything will do
+			Command::Raw(data) => vec![Frame::new(data, None)],
+			Command::DownloadHistory(data) => vec![Frame { history: true, ..Frame::new(data, Some(MessageKind::Scan)) }],
+			Command::Disconnect => vec![],
+		}
+	}
+}
+
+/// What came from the device
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+	Scan(ScanResult),
+	Calibrated,
+	PowerLevel(i16),
+	/// Sent along with every power level
+	Session { scan_count: usize, duration_secs: u64 },
+	/// Sent along with every power level, after [Event::Session]
+	LinkStats(LinkStats),
+	DeviceInfo(Vec<i16>),
+	/// An AB message of no known kind, only with [DeviceConfig::emit_unknown]
+	Unknown(Vec<u8>),
+	/// Something the user should know about that doesn't stop anything
+	Warning(String),
+	/// A command failed, but the device loop carries on
+	Error(String),
+	/// Subscribed and listening, sent once at the start
+	Ready,
+	/// Sent every [HEARTBEAT_INTERVAL] to show the loop isn't stuck. Not meant for output.
+	Heartbeat,
+}
+
+/// Counts for diagnosing a flaky link, since the [device_loop] started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+	/// Notifications received, before reassembly
+	pub notifications: usize,
+	/// Messages of no known kind, ours or not
+	pub unknown: usize,
+	/// Messages that were given up waiting for and didn't end with their checksum
+	pub checksum_failures: usize,
+	/// Scan messages that couldn't be parsed
+	pub bad_scans: usize,
+	/// Known messages that arrived while a different answer was expected
+	pub unsolicited: usize,
+	pub write_retries: usize,
+	/// Commands that got no answer in time
+	pub timeouts: usize,
+	/// Commands that did get an answer
+	pub answered: usize,
+	/// Time from writing a command to its answer, over all answered commands
+	pub latency_min: Option<Duration>,
+	pub latency_max: Option<Duration>,
+	pub latency_total: Duration,
+}
+
+impl LinkStats {
+	
+	fn record_latency(&mut self, latency: Duration) {
+		self.answered += 1;
+		self.latency_min = Some(self.latency_min.map_or(latency, |min| min.min(latency)));
+		self.latency_max = Some(self.latency_max.map_or(latency, |max| max.max(latency)));
+		self.latency_total += latency;
+	}
+	
+	pub fn latency_avg(&self) -> Option<Duration> {
+		(self.answered > 0).then(|| self.latency_total / self.answered as u32)
+	}
+	
+}
+
+/// Sends commands to a running [device_loop]
+#[derive(Clone)]
+pub struct DeviceHandle {
+	tx: mpsc::UnboundedSender<Command>,
+	/// Frames sent but not yet answered
+	pending: Arc<AtomicUsize>,
+	/// Signalled whenever the last pending frame has been answered, and when the loop ends
+	idle: Arc<Notify>,
+	failures: Arc<Mutex<Failures>>,
+}
+
+/// Why frames failed (went unanswered, or the answer was unusable), oldest first. Only kept
+/// while a [DeviceHandle::run] is waiting, so a long session doesn't pile them up.
+#[derive(Default)]
+struct Failures {
+	/// [DeviceHandle::run] calls waiting
+	runs: usize,
+	reasons: Vec<String>,
+}
+
+/// Counts a [DeviceHandle::run] as waiting for as long as it lives, even if it's cancelled
+struct RunGuard<'a>(&'a Mutex<Failures>);
+
+impl<'a> RunGuard<'a> {
+	/// Starts waiting, returns how many reasons there were already
+	fn new(failures: &'a Mutex<Failures>) -> (Self, usize) {
+		let mut inner = failures.lock().unwrap();
+		inner.runs += 1;
+		(Self(failures), inner.reasons.len())
+	}
+}
+
+impl Drop for RunGuard<'_> {
+	fn drop(&mut self) {
+		let mut inner = self.0.lock().unwrap();
+		inner.runs -= 1;
+		if inner.runs == 0 {
+			inner.reasons.clear();
+		}
+	}
+}
+
+/// The [device_loop] end of a [DeviceHandle]
+pub struct DeviceCommands {
+	rx: mpsc::UnboundedReceiver<Command>,
+	pending: Arc<AtomicUsize>,
+	idle: Arc<Notify>,
+	failures: Arc<Mutex<Failures>>,
+}
+
+pub fn device_channel() -> (DeviceHandle, DeviceCommands) {
+	let (tx, rx) = mpsc::unbounded_channel();
+	let pending = Arc::new(AtomicUsize::new(0));
+	let idle = Arc::new(Notify::new());
+	let failures = Arc::new(Mutex::new(Failures::default()));
+	(
+		DeviceHandle { tx, pending: pending.clone(), idle: idle.clone(), failures: failures.clone() },
+		DeviceCommands { rx, pending, idle, failures },
+	)
+}
+
+impl DeviceHandle {
+	
+	/// Whether the device loop has finished
+	pub fn is_closed(&self) -> bool {
+		self.tx.is_closed()
+	}
+	
+	pub fn send(&self, cmd: Command) -> Result<(), anyhow::Error> {
+		let frames = cmd.frames().len();
+		self.pending.fetch_add(frames, SeqCst);
+		self.tx.send(cmd).map_err(|_| {
+			self.pending.fetch_sub(frames, SeqCst);
+			anyhow::Error::msg("Device connection is closed")
+		})
+	}
+	
+	/// Wait until every command sent so far has been written and answered (or the loop has finished)
+	pub async fn wait_idle(&self) {
+		loop {
+			let notified = self.idle.notified();
+			if self.pending.load(SeqCst) == 0 || self.is_closed() { return; }
+			notified.await;
+		}
+	}
+	
+	/// Send a command and wait for it (and anything sent before it) to be answered, failing if any
+	/// of that went unanswered, got an unusable answer, or the loop finished first
+	pub async fn run(&self, cmd: Command) -> Result<(), anyhow::Error> {
+		let (_guard, before) = RunGuard::new(&self.failures);
+		self.send(cmd)?;
+		self.wait_idle().await;
+		let failures = self.failures.lock().unwrap().reasons[before..].join("; ");
+		if !failures.is_empty() {
+			return Err(anyhow::Error::msg(failures));
+		}
+		if self.pending.load(SeqCst) > 0 {
+			return Err(anyhow::Error::msg("Device connection is closed"));
+		}
+		Ok(())
+	}
+	
+}
+
+impl DeviceCommands {
+	fn answered(&self) {
+		if self.pending.fetch_sub(1, SeqCst) == 1 {
+			self.idle.notify_waiters();
+		}
+	}
+	/// For [DeviceHandle::run], before [DeviceCommands::answered]
+	fn failed(&self, reason: String) {
+		let mut failures = self.failures.lock().unwrap();
+		if failures.runs > 0 {
+			failures.reasons.push(reason);
+		}
+	}
+}
+
+/// Whatever was still pending won't be answered now
+impl Drop for DeviceCommands {
+	fn drop(&mut self) {
+		self.rx.close();
+		self.idle.notify_waiters();
+	}
+}
+
+pub struct DeviceConfig {
+	/// Turn unrecognized AB messages into [Event::Unknown]
+	pub emit_unknown: bool,
+	/// Attach the raw message to each [ScanResult], for bug reports
+	pub keep_raw: bool,
+	pub timing: Option<TimingLog>,
+	pub max_write_errors: usize,
+	pub write_error_window: Duration,
+	/// Give up on a command that gets no answer for this long, `None` to wait forever
+	pub command_timeout: Option<Duration>,
+	/// Send a scan command again this many times if no scan arrives within `scan_retry_timeout`.
+	/// With 0, scans are treated like other commands.
+	pub scan_retries: usize,
+	pub scan_retry_timeout: Duration,
+	/// Warn when a scan is sent longer than this after the last calibration (or with none yet)
+	pub calibration_max_age: Option<Duration>,
+}
+
+/// Same as the command line defaults
+impl Default for DeviceConfig {
+	fn default() -> Self {
+		Self {
+			emit_unknown: false,
+			keep_raw: false,
+			timing: None,
+			max_write_errors: 3,
+			write_error_window: Duration::from_secs(30),
+			command_timeout: Some(Duration::from_secs(10)),
+			scan_retries: 2,
+			scan_retry_timeout: Duration::from_secs(5),
+			calibration_max_age: None,
+		}
+	}
+}
+
+/// Writes commands to the device, riding out occasional failed writes
+struct CommandWriter<'a, T: Transport> {
+	transport: &'a T,
+	timing: Option<&'a TimingLog>,
+	events: &'a mpsc::UnboundedSender<Event>,
+	/// Times of recent failed writes
+	errors: VecDeque<Instant>,
+	max_errors: usize,
+	error_window: Duration,
+	/// Failed writes that were tried again
+	retries: usize,
+}
+
+impl<T: Transport> CommandWriter<'_, T> {
+	
+	/// Retries after a short delay unless more than `max_errors` writes failed within `error_window`
+	async fn write(&mut self, cmd: &[u8]) -> Result<(), anyhow::Error> {
+		loop {
+			if let Some(tl) = self.timing { tl.record("write", cmd); }
+			let Err(e) = self.transport.write(cmd).await else { return Ok(()) };
+			if e.is::<CharacteristicLost>() {
+				self.events.send(Event::Error(e.to_string())).ok();
+				self.transport.disconnect().await.ok();
+				return Err(e);
+			}
+			let now = Instant::now();
+			self.errors.push_back(now);
+			self.errors.retain(|t| now.duration_since(*t) <= self.error_window);
+			let recent = self.errors.len();
+			if recent > self.max_errors {
+				return Err(anyhow::Error::msg(format!("Giving up after {recent} failed writes: {e}")));
+			}
+			self.events.send(Event::Warning(format!("Write failed ({e}), retrying"))).ok();
+			self.retries += 1;
+			tokio::time::sleep(WRITE_RETRY_DELAY).await;
+		}
+	}
+	
+}
+
+/// Talks to the device until it goes away (or a [Command::Disconnect]).
+///
+/// One frame is written at a time; the rest wait in a queue until the device answers it
+/// with the expected kind of message (or --command-timeout runs out).
+pub async fn device_loop<T: Transport>(
+	transport: T,
+	config: DeviceConfig,
+	mut commands: DeviceCommands,
+	events: mpsc::UnboundedSender<Event>,
+) -> Result<(), anyhow::Error> {
+	
+	transport.subscribe().await?;
+	let mut notifications = transport.notifications().await?;
+	let connected_at = Instant::now();
+	events.send(Event::Ready).ok();
+	
+	let mut writer = CommandWriter {
+		transport: &transport,
+		timing: config.timing.as_ref(),
+		events: &events,
+		errors: VecDeque::new(),
+		max_errors: config.max_write_errors,
+		error_window: config.write_error_window,
+		retries: 0,
+	};
+	let mut queue: VecDeque<Frame> = VecDeque::new();
+	// Written and waiting for an answer
+	let mut in_flight: Option<Frame> = None;
+	// When to give up on the frame in flight (or send it again), if ever
+	let mut deadline: Option<tokio::time::Instant> = None;
+	// Once every handle is gone only the notifications are left to handle
+	let mut accepting = true;
+	
+	let mut count: usize = 0;
+	let mut stats = LinkStats::default();
+	let mut scan_times: VecDeque<Instant> = VecDeque::new();
+	// Tokio's clock rather than std's, so tests can skip ahead
+	let mut calibrated_at: Option<tokio::time::Instant> = None;
+	let mut reassembler = Reassembler::new(FRAGMENT_WINDOW);
+	let emit = |event: Event| { events.send(event).ok(); };
+	let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+	
+	loop {
+		let mut received = None;
+		tokio::select! {
+			_ = heartbeat.tick() => emit(Event::Heartbeat),
+			cmd = commands.rx.recv(), if accepting => match cmd {
+				None => accepting = false,
+				Some(Command::Disconnect) => {
+					let unanswered = queue.len() + usize::from(in_flight.is_some());
+					if unanswered > 0 {
+						warn!("Disconnecting with {unanswered} command(s) not written or answered yet");
+					}
+					debug!("Disconnecting");
+					transport.disconnect().await?;
+					break;
+				},
+				Some(cmd) => queue.extend(cmd.frames().into_iter().map(|frame| match frame.is_live_scan() {
+					true => Frame { retries: config.scan_retries, ..frame },
+					false => frame,
+				})),
+			},
+			_ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)), if in_flight.is_some() && deadline.is_some() => {
+				match in_flight.take() {
+					Some(mut frame) if frame.retries > 0 => {
+						warn!("No answer to command {}, sending it again", hex::encode_upper(&frame.data));
+						frame.retries -= 1;
+						// Still pending, so it goes out next
+						queue.push_front(frame);
+					},
+					Some(frame) => {
+						stats.timeouts += 1;
+						let msg = format!("No answer to command {}", hex::encode_upper(&frame.data));
+						commands.failed(msg.clone());
+						emit(Event::Error(msg));
+						commands.answered();
+					},
+					None => {},
+				}
+			},
+			value = notifications.next() => {
+				let Some(value) = value else { break };
+				debug!("Received: {:x?}", value);
+				stats.notifications += 1;
+				if let Some(tl) = &config.timing { tl.record("notify", &value); }
+				reassembler.batch = in_flight.as_ref().is_some_and(|f| f.history);
+				received = reassembler.push(value);
+			},
+			_ = tokio::time::sleep_until(reassembler.deadline().map_or_else(tokio::time::Instant::now, Into::into)), if reassembler.deadline().is_some() => {
+				received = reassembler.flush();
+			},
+		}
+		
+		if let Some(msg) = received {
+			let kind = message_kind(&msg);
+			match kind {
+				Some(MessageKind::Scan) if in_flight.as_ref().is_some_and(|f| f.history) => {
+					debug!("Is stored scan batch (AB44)");
+					for (idx, rec) in split_scan_records(&msg).enumerate() {
+						match parse_scan_result(rec, idx + 1) {
+							Ok(result) => {
+								let raw = config.keep_raw.then(|| rec.to_vec());
+								let result = ScanResult { id: Uuid::new_v4(), historical: true, raw, ..result };
+								debug!("stored result = {result:?}");
+								emit(Event::Scan(result));
+							},
+							Err(e) => {
+								stats.bad_scans += 1;
+								commands.failed(e.to_string());
+								emit(Event::Error(e.to_string()));
+							},
+						}
+					}
+				},
+				Some(MessageKind::Scan) => {
+					debug!("Is color scan result (AB44)");
+					
+					match parse_scan_result(&msg, count + 1) {
+						Ok(result) => {
+							count += 1;
+							
+							let now = Instant::now();
+							scan_times.push_back(now);
+							scan_times.retain(|t| now.duration_since(*t) <= SCAN_RATE_WINDOW);
+							let rate = match scan_times.front() {
+								Some(first) if scan_times.len() > 1 => Some((scan_times.len() - 1) as f32 / now.duration_since(*first).as_secs_f32()),
+								_ => None,
+							};
+							
+							let label = in_flight.as_ref().and_then(|f| f.label.clone());
+							let raw = config.keep_raw.then(|| msg.clone());
+							let result = ScanResult { id: Uuid::new_v4(), rate, label, raw, ..result };
+							debug!("result = {result:?}");
+							emit(Event::Scan(result));
+						},
+						// Rather than confidently wrong numbers
+						Err(e) => {
+							stats.bad_scans += 1;
+							if in_flight.as_ref().is_some_and(|f| f.answered_by(kind)) {
+								commands.failed(e.to_string());
+							}
+							emit(Event::Error(e.to_string()));
+						},
+					}
+				},
+				Some(MessageKind::Calibrated) => {
+					debug!("Is calibration response (AB202E)");
+					calibrated_at = Some(tokio::time::Instant::now());
+					emit(Event::Calibrated);
+				},
+				Some(MessageKind::PowerLevel) => {
+					debug!("Is power level response (AB200B)");
+					emit(Event::PowerLevel(LittleEndian::read_i16(&msg[6..8])));
+					emit(Event::Session {
+						scan_count: count,
+						duration_secs: connected_at.elapsed().as_secs(),
+					});
+					emit(Event::LinkStats(LinkStats { write_retries: writer.retries, checksum_failures: reassembler.checksum_failures, ..stats }));
+				},
+				Some(MessageKind::DeviceInfo) => {
+					debug!("Is device info response (AB4000)");
+					emit(Event::DeviceInfo((10..25).map(|idx| LittleEndian::read_i16(&msg[idx..(idx + 2)])).collect()));
+				},
+				// Not even ours, so it doesn't answer anything either
+				None if msg.first() != Some(&0xAB) => {
+					warn!("Unknown message: {:x?}", msg);
+					stats.unknown += 1;
+					continue;
+				},
+				None => {
+					stats.unknown += 1;
+					if config.emit_unknown {
+						emit(Event::Unknown(msg));
+					} else {
+						emit(Event::Warning(format!("Unknown message: {}", hex::encode_upper(&msg))));
+					}
+				},
+			}
+			
+			match (&in_flight, kind) {
+				(Some(frame), _) if frame.answered_by(kind) => {
+					if let Some(at) = frame.sent_at {
+						let latency = at.elapsed();
+						debug!("Command {} answered in {latency:?}", hex::encode_upper(&frame.data));
+						stats.record_latency(latency);
+					}
+					in_flight = None;
+					commands.answered();
+				},
+				// E.g. a scan from the device's button, which must not take the place of the answer
+				(Some(frame), Some(kind)) => {
+					warn!("Unsolicited {kind:?} message while waiting for an answer to {}", hex::encode_upper(&frame.data));
+					stats.unsolicited += 1;
+				},
+				_ => {},
+			}
+		}
+		
+		if in_flight.is_none() {
+			if let Some(mut frame) = queue.pop_front() {
+				debug!("write command: {:x?}", frame.data);
+				// Checked here rather than when queued, so a calibration queued just before counts
+				if let Some(max_age) = config.calibration_max_age.filter(|_| frame.is_live_scan() && frame.sent_at.is_none()) {
+					match calibrated_at.map(|at| at.elapsed()) {
+						None => emit(Event::Warning("Not calibrated since connecting".to_string())),
+						Some(age) if age > max_age => emit(Event::Warning(format!("Last calibrated {} minutes ago", age.as_secs() / 60))),
+						Some(_) => {},
+					}
+				}
+				writer.write(&frame.data).await?;
+				let timeout = match frame.is_live_scan() && config.scan_retries > 0 {
+					true => Some(config.scan_retry_timeout),
+					false => config.command_timeout,
+				};
+				deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+				frame.sent_at = Some(Instant::now());
+				in_flight = Some(frame);
+			}
+		}
+	}
+	
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Mutex;
+	use tokio::task::JoinHandle;
+	use crate::testing::*;
+	use super::*;
+	
+	struct Harness {
+		device: mpsc::UnboundedSender<Vec<u8>>,
+		written: Arc<Mutex<Vec<Vec<u8>>>>,
+		handle: DeviceHandle,
+		events: mpsc::UnboundedReceiver<Event>,
+		task: JoinHandle<Result<(), anyhow::Error>>,
+	}
+	
+	async fn start(emit_unknown: bool, failures: usize) -> Harness {
+		let config = DeviceConfig { emit_unknown, max_write_errors: 1, ..Default::default() };
+		start_failing(config, failures).await
+	}
+	
+	async fn start_with(config: DeviceConfig) -> Harness {
+		start_failing(config, 0).await
+	}
+	
+	/// Starts the loop and waits for it to be ready
+	async fn start_failing(config: DeviceConfig, failures: usize) -> Harness {
+		let (transport, device) = FakeTransport::new();
+		let transport = transport.failing(failures);
+		let written = transport.written.clone();
+		let (handle, commands) = device_channel();
+		let (events_tx, events) = mpsc::unbounded_channel();
+		let task = tokio::spawn(device_loop(transport, config, commands, events_tx));
+		let mut h = Harness { device, written, handle, events, task };
+		assert_eq!(h.event().await, Event::Ready);
+		h
+	}
+	
+	impl Harness {
+		
+		/// The next event, with the random id of a scan cleared so it can be compared
+		async fn event(&mut self) -> Event {
+			let event = tokio::time::timeout(Duration::from_secs(1), self.events.recv()).await
+				.expect("no event").expect("events closed");
+			match event {
+				Event::Scan(result) => {
+					assert!(!result.id.is_nil());
+					Event::Scan(ScanResult { id: Uuid::nil(), ..result })
+				},
+				event => event,
+			}
+		}
+		
+		/// Wait for the loop to have written this many frames in total
+		async fn written(&self, n: usize) -> Vec<Vec<u8>> {
+			for _ in 0..1000 {
+				let written = self.written.lock().unwrap().clone();
+				if written.len() >= n { return written; }
+				tokio::time::sleep(Duration::from_millis(1)).await;
+			}
+			panic!("expected {n} writes, got {:?}", self.written.lock().unwrap());
+		}
+		
+		/// Feed notifications and stop, collecting whatever events came out
+		async fn finish(mut self) -> (Result<(), anyhow::Error>, Vec<Event>) {
+			drop(self.device);
+			drop(self.handle);
+			let res = self.task.await.unwrap();
+			let mut events = Vec::new();
+			while let Ok(event) = self.events.try_recv() { events.push(event); }
+			(res, events)
+		}
+		
+	}
+	
+	#[tokio::test]
+	async fn scan_becomes_event() {
+		let mut h = start(false, 0).await;
+		h.device.send(scan_frame()).unwrap();
+		assert_eq!(h.event().await, Event::Scan(scan_result(1)));
+		h.device.send(scan_frame()).unwrap();
+		let Event::Scan(second) = h.event().await else { panic!("expected a scan") };
+		assert_eq!(second.idx, 2);
+		assert!(second.rate.is_some());
+		let (res, events) = h.finish().await;
+		res.unwrap();
+		assert!(events.is_empty());
+	}
+	
+	#[tokio::test]
+	async fn commands_sent_before_start() {
+		// Like --scan on launch: queued before the loop has even subscribed
+		let (transport, device) = FakeTransport::new();
+		let transport = transport.replying(&device, &SCAN_CMD, scan_frame());
+		let (handle, commands) = device_channel();
+		handle.send(Command::Scan).unwrap();
+		let (events_tx, mut events) = mpsc::unbounded_channel();
+		let task = tokio::spawn(device_loop(transport, DeviceConfig::default(), commands, events_tx));
+		
+		assert_eq!(events.recv().await, Some(Event::Ready));
+		let Some(Event::Scan(result)) = events.recv().await else { panic!("expected a scan") };
+		assert_eq!(result.idx, 1);
+		
+		handle.send(Command::Disconnect).unwrap();
+		task.await.unwrap().unwrap();
+	}
+	
+	#[tokio::test]
+	async fn command_latency() {
+		let (transport, device) = FakeTransport::new();
+		let transport = transport
+			.replying(&device, &SCAN_CMD, scan_frame())
+			.replying(&device, &INFO_CMD, info_frame())
+			.replying(&device, &BATTERY_CMD, power_frame());
+		let (handle, commands) = device_channel();
+		handle.send(Command::Scan).unwrap();
+		handle.send(Command::Scan).unwrap();
+		handle.send(Command::Status).unwrap();
+		let (events_tx, mut events) = mpsc::unbounded_channel();
+		let task = tokio::spawn(device_loop(transport, DeviceConfig::default(), commands, events_tx));
+		
+		let stats = loop {
+			match events.recv().await {
+				Some(Event::LinkStats(stats)) => break stats,
+				Some(_) => {},
+				None => panic!("no link stats"),
+			}
+		};
+		// Both scans and the info request, the power level itself being the answer in progress
+		assert_eq!(stats.answered, 3);
+		// The actual times depend on the machine
+		assert!(stats.latency_min.is_some());
+		assert!(stats.latency_min <= stats.latency_avg() && stats.latency_avg() <= stats.latency_max);
+		assert!(stats.latency_total >= stats.latency_max.unwrap());
+		
+		handle.send(Command::Disconnect).unwrap();
+		task.await.unwrap().unwrap();
+	}
+	
+	#[tokio::test]
+	async fn characteristic_lost() {
+		let (transport, _device) = FakeTransport::new();
+		let (handle, commands) = device_channel();
+		handle.send(Command::Scan).unwrap();
+		let (events_tx, mut events) = mpsc::unbounded_channel();
+		let res = device_loop(transport.lost(), DeviceConfig::default(), commands, events_tx).await;
+		// Fatal at once, rather than after --max-write-errors retries
+		assert!(res.unwrap_err().is::<CharacteristicLost>());
+		assert_eq!(events.recv().await, Some(Event::Ready));
+		assert_eq!(events.recv().await, Some(Event::Error("Write characteristic lost".to_string())));
+	}
+	
+	#[tokio::test]
+	async fn raw_scans() {
+		let mut h = start_with(DeviceConfig { keep_raw: true, ..Default::default() }).await;
+		h.device.send(scan_frame()).unwrap();
+		assert_eq!(h.event().await, Event::Scan(ScanResult { raw: Some(scan_frame()), ..scan_result(1) }));
+		h.finish().await.0.unwrap();
+	}
+	
+	#[tokio::test]
+	async fn fragmented_scan() {
+		let mut h = start(false, 0).await;
+		let scan = scan_frame();
+		h.device.send(scan[..20].to_vec()).unwrap();
+		h.device.send(scan[20..].to_vec()).unwrap();
+		assert_eq!(h.event().await, Event::Scan(scan_result(1)));
+		h.finish().await.0.unwrap();
+	}
+	
+	#[tokio::test]
+	async fn responses_become_events() {
+		let h = start(false, 0).await;
+		h.device.send(calibrated_frame()).unwrap();
+		h.device.send(info_frame()).unwrap();
+		h.device.send(power_frame()).unwrap();
+		let (res, events) = h.finish().await;
+		res.unwrap();
+		// Unsolicited, so no latency either
+		assert_eq!(events, vec![
+			Event::Calibrated,
+			Event::DeviceInfo(vec![0x0100, 0x0201, 0x0302, 0x0403, 0x0504, 0x0605, 0x0706, 0x0807, 0x0908, 0x0A09, 0x0B0A, 0x0C0B, 0x0D0C, 0x0E0D, 0x0F0E]),
+			Event::PowerLevel(41),
+			Event::Session { scan_count: 0, duration_secs: 0 },
+			Event::LinkStats(LinkStats { notifications: 3, ..Default::default() }),
+		]);
+	}
+	
+	#[tokio::test]
+	async fn malformed_frames() {
+		let h = start(true, 0).await;
+		// Not ours, dropped
+		h.device.send(vec![0x01, 0x02, 0x03]).unwrap();
+		// Ours but of no known kind
+		h.device.send(vec![0xAB, 0x99, 0x00]).unwrap();
+		// A truncated scan that never gets its tail
+		h.device.send(scan_frame()[..10].to_vec()).unwrap();
+		let (res, events) = h.finish().await;
+		res.unwrap();
+		assert_eq!(events, vec![Event::Unknown(vec![0xAB, 0x99, 0x00])]);
+	}
+	
+	#[tokio::test]
+	async fn unknown_is_a_warning_by_default() {
+		let h = start(false, 0).await;
+		h.device.send(vec![0xAB, 0x99, 0x00]).unwrap();
+		let (res, events) = h.finish().await;
+		res.unwrap();
+		assert_eq!(events, vec![Event::Warning("Unknown message: AB9900".to_string())]);
+	}
+	
+	#[tokio::test]
+	async fn writes_one_frame_at_a_time() {
+		let mut h = start(false, 0).await;
+		h.handle.send(Command::Status).unwrap();
+		h.handle.send(Command::Scan).unwrap();
+		
+		// Only the first frame goes out immediately
+		assert_eq!(h.written(1).await, vec![INFO_CMD.clone()]);
+		tokio::time::sleep(Duration::from_millis(20)).await;
+		assert_eq!(h.written.lock().unwrap().len(), 1);
+		
+		// Each answer releases the next queued frame
+		h.device.send(info_frame()).unwrap();
+		assert!(matches!(h.event().await, Event::DeviceInfo(_)));
+		assert_eq!(h.written(2).await[1], *BATTERY_CMD);
+		
+		// Neither noise that isn't ours nor an unrelated message answers the battery request
+		h.device.send(vec![0x01]).unwrap();
+		h.device.send(calibrated_frame()).unwrap();
+		assert_eq!(h.event().await, Event::Calibrated);
+		tokio::time::sleep(Duration::from_millis(20)).await;
+		assert_eq!(h.written.lock().unwrap().len(), 2);
+		h.device.send(power_frame()).unwrap();
+		assert_eq!(h.event().await, Event::PowerLevel(41));
+		assert_eq!(h.written(3).await[2], *SCAN_CMD);
+		
+		h.device.send(scan_frame()).unwrap();
+		h.handle.wait_idle().await;
+		
+		// With nothing in flight the next command goes out right away
+		h.handle.send(Command::Raw(vec![0xAB, 0x01])).unwrap();
+		assert_eq!(h.written(4).await[3], vec![0xAB, 0x01]);
+		assert_eq!(h.written.lock().unwrap().len(), 4);
+		
+		h.finish().await.0.unwrap();
+	}
+	
+	#[tokio::test]
+	async fn unsolicited_scan_during_status() {
+		let mut h = start(false, 0).await;
+		h.handle.send(Command::Status).unwrap();
+		h.handle.send(Command::Scan).unwrap();
+		h.written(1).await;
+		
+		// Someone pressed the button while the device info was on its way
+		h.device.send(scan_frame()).unwrap();
+		assert_eq!(h.event().await, Event::Scan(scan_result(1)));
+		tokio::time::sleep(Duration::from_millis(20)).await;
+		assert_eq!(h.written.lock().unwrap().len(), 1);
+		h.device.send(info_frame()).unwrap();
+		assert!(matches!(h.event().await, Event::DeviceInfo(_)));
+		h.device.send(power_frame()).unwrap();
+		assert_eq!(h.event().await, Event::PowerLevel(41));
+		
+		// Only now does the scan command go out
+		assert_eq!(h.written(3).await, vec![INFO_CMD.clone(), BATTERY_CMD.clone(), SCAN_CMD.clone()]);
+		h.finish().await.0.unwrap();
+	}
+	
+	#[tokio::test]
+	async fn wait_idle_waits_for_answers() {
+		let h = start(false, 0).await;
+		h.handle.send(Command::Calibrate).unwrap();
+		h.written(1).await;
+		assert!(tokio::time::timeout(Duration::from_millis(20), h.handle.wait_idle()).await.is_err());
+		h.device.send(calibrated_frame()).unwrap();
+		tokio::time::timeout(Duration::from_secs(1), h.handle.wait_idle()).await.unwrap();
+		h.finish().await.0.unwrap();
+	}
+	
+	#[tokio::test]
+	async fn history_download() {
+		let mut h = start(false, 0).await;
+		let command = vec![0xAB, 0x45, 0x00, 0x00];
+		h.handle.send(Command::DownloadHistory(command.clone())).unwrap();
+		h.handle.send(Command::Scan).unwrap();
+		assert_eq!(h.written(1).await, vec![command]);
+		
+		// Two stored scans and a stray tail in one answer
+		let mut batch = [scan_frame(), scan_frame()].concat();
+		batch.extend_from_slice(&[0xAB, 0x44, 0x00]);
+		h.device.send(batch).unwrap();
+		for idx in 1..=2 {
+			assert_eq!(h.event().await, Event::Scan(ScanResult { historical: true, ..scan_result(idx) }));
+		}
+		
+		// Live scans carry on as usual afterwards
+		assert_eq!(h.written(2).await[1], *SCAN_CMD);
+		h.device.send(scan_frame()).unwrap();
+		assert_eq!(h.event().await, Event::Scan(scan_result(1)));
+		
+		let (res, events) = h.finish().await;
+		res.unwrap();
+		assert!(events.is_empty());
+	}
+	
+	#[tokio::test]
+	async fn labeled_scan() {
+		let mut h = start(false, 0).await;
+		h.handle.send(Command::ScanLabeled("sample-001".to_string())).unwrap();
+		h.handle.send(Command::Scan).unwrap();
+		assert_eq!(h.written(1).await, vec![SCAN_CMD.clone()]);
+		h.device.send(scan_frame()).unwrap();
+		assert_eq!(h.event().await, Event::Scan(ScanResult { label: Some("sample-001".to_string()), ..scan_result(1) }));
+		h.written(2).await;
+		h.device.send(scan_frame()).unwrap();
+		let Event::Scan(second) = h.event().await else { panic!("expected a scan") };
+		assert_eq!(second.label, None);
+		h.finish().await.0.unwrap();
+	}
+	
+	#[tokio::test]
+	async fn command_timeout() {
+		let mut h = start_with(DeviceConfig { command_timeout: Some(Duration::from_millis(50)), ..Default::default() }).await;
+		h.handle.send(Command::Calibrate).unwrap();
+		h.handle.send(Command::Scan).unwrap();
+		assert_eq!(h.written(1).await, vec![CALIBRATE_CMD.clone()]);
+		assert_eq!(h.event().await, Event::Error("No answer to command AB202E000200904F".to_string()));
+		
+		// The queue moves on
+		assert_eq!(h.written(2).await[1], *SCAN_CMD);
+		h.device.send(scan_frame()).unwrap();
+		assert_eq!(h.event().await, Event::Scan(scan_result(1)));
+		tokio::time::timeout(Duration::from_secs(1), h.handle.wait_idle()).await.unwrap();
+		h.finish().await.0.unwrap();
+	}
+	
+	#[tokio::test]
+	async fn run_reports_failures() {
+		let config = DeviceConfig { command_timeout: Some(Duration::from_millis(50)), ..Default::default() };
+		let h = start_with(config).await;
+		let err = h.handle.run(Command::Calibrate).await.unwrap_err();
+		assert_eq!(err.to_string(), "No answer to command AB202E000200904F");
+		
+		// Answered this time, and the earlier failure doesn't count again
+		let reply = async {
+			h.written(2).await;
+			h.device.send(calibrated_frame()).unwrap();
+		};
+		let (res, ()) = tokio::join!(h.handle.run(Command::Calibrate), reply);
+		res.unwrap();
+		
+		// Nothing kept once no one is waiting
+		h.handle.send(Command::Calibrate).unwrap();
+		tokio::time::timeout(Duration::from_secs(1), h.handle.wait_idle()).await.unwrap();
+		assert!(h.handle.failures.lock().unwrap().reasons.is_empty());
+		
+		// Nor does it hang once the loop is gone
+		let handle = h.handle.clone();
+		h.finish().await.0.unwrap();
+		assert_eq!(handle.run(Command::Scan).await.unwrap_err().to_string(), "Device connection is closed");
+	}
+	
+	#[tokio::test]
+	async fn scan_retries() {
+		let config = DeviceConfig { scan_retries: 1, scan_retry_timeout: Duration::from_millis(50), ..Default::default() };
+		let mut h = start_with(config).await;
+		h.handle.send(Command::Scan).unwrap();
+		assert_eq!(h.written(2).await, vec![SCAN_CMD.clone(), SCAN_CMD.clone()]);
+		
+		// Only an error once the retries are used up
+		assert_eq!(h.event().await, Event::Error("No answer to command AB440000000036001864".to_string()));
+		assert_eq!(h.written.lock().unwrap().len(), 2);
+		tokio::time::timeout(Duration::from_secs(1), h.handle.wait_idle()).await.unwrap();
+		h.finish().await.0.unwrap();
+	}
+	
+	#[tokio::test(start_paused = true)]
+	async fn calibration_reminder() {
+		let config = DeviceConfig { calibration_max_age: Some(Duration::from_secs(60 * 60)), ..Default::default() };
+		let mut h = start_with(config).await;
+		h.handle.send(Command::Scan).unwrap();
+		assert_eq!(h.event().await, Event::Warning("Not calibrated since connecting".to_string()));
+		h.device.send(scan_frame()).unwrap();
+		assert_eq!(h.event().await, Event::Scan(scan_result(1)));
+		
+		h.handle.send(Command::Calibrate).unwrap();
+		h.written(2).await;
+		h.device.send(calibrated_frame()).unwrap();
+		assert_eq!(h.event().await, Event::Calibrated);
+		h.handle.send(Command::Scan).unwrap();
+		h.written(3).await;
+		h.device.send(scan_frame()).unwrap();
+		// No warning before it
+		assert!(matches!(h.event().await, Event::Scan(_)));
+		
+		tokio::time::sleep(Duration::from_secs(61 * 60)).await;
+		h.handle.send(Command::Scan).unwrap();
+		// The heartbeats kept coming meanwhile
+		let event = loop {
+			match h.event().await {
+				Event::Heartbeat => continue,
+				event => break event,
+			}
+		};
+		assert_eq!(event, Event::Warning("Last calibrated 61 minutes ago".to_string()));
+		h.finish().await.0.unwrap();
+	}
+	
+	#[tokio::test]
+	async fn disconnect_ends_loop() {
+		let h = start(false, 0).await;
+		h.handle.send(Command::Disconnect).unwrap();
+		tokio::time::timeout(Duration::from_secs(1), h.task).await.unwrap().unwrap().unwrap();
+	}
+	
+	#[tokio::test]
+	async fn write_errors() {
+		// One failure is ridden out
+		let h = start(false, 1).await;
+		h.handle.send(Command::Scan).unwrap();
+		assert_eq!(h.written(1).await, vec![SCAN_CMD.clone()]);
+		let (res, events) = h.finish().await;
+		res.unwrap();
+		assert_eq!(events, vec![Event::Warning("Write failed (write failed), retrying".to_string())]);
+		
+		// Two within the window are fatal
+		let h = start(false, 2).await;
+		h.handle.send(Command::Scan).unwrap();
+		let res = tokio::time::timeout(Duration::from_secs(5), h.task).await.unwrap().unwrap();
+		assert!(res.is_err());
+	}
+	
+}
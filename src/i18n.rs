@@ -0,0 +1,70 @@
+//! Translations of the words in text output. JSON and line output stay as they are.
+
+use std::sync::OnceLock;
+
+/// (language, key, text). A new language only needs its rows here; missing keys fall back to English.
+const TABLE: &[(&str, &str, &str)] = &[
+	("en", "scan_result", "Scan result #"),
+	("en", "stored_scan", "Stored scan #"),
+	("en", "update", "Update"),
+	("en", "devices_found", "Devices found"),
+	("en", "unnamed", "(unnamed)"),
+	("en", "capable", "capable"),
+	("en", "warning", "Warning"),
+	("en", "error", "Error"),
+	("en", "ready_hint", "Ready. Press the device button to scan."),
+	("en", "rate", "Rate"),
+	("en", "gamut", "Gamut"),
+	
+	("de", "scan_result", "Messung #"),
+	("de", "stored_scan", "Gespeicherte Messung #"),
+	("de", "update", "Aktualisierung"),
+	("de", "devices_found", "Gefundene Geräte"),
+	("de", "unnamed", "(unbenannt)"),
+	("de", "capable", "kompatibel"),
+	("de", "warning", "Warnung"),
+	("de", "error", "Fehler"),
+	("de", "ready_hint", "Bereit. Zum Messen die Taste am Gerät drücken."),
+	("de", "rate", "Messrate"),
+	("de", "gamut", "Farbraum"),
+];
+
+static LANG: OnceLock<&'static str> = OnceLock::new();
+
+/// Pick the language for [t], once, before any output
+pub fn set_lang(code: &str) -> Result<(), anyhow::Error> {
+	let Some((lang, _, _)) = TABLE.iter().find(|(lang, _, _)| lang.eq_ignore_ascii_case(code)) else {
+		return Err(anyhow::Error::msg(format!("Unknown language: {code}")));
+	};
+	LANG.set(lang).map_err(|_| anyhow::Error::msg("Language already set"))
+}
+
+/// The text for `key` in the current language
+pub fn t(key: &'static str) -> &'static str {
+	lookup(LANG.get().copied().unwrap_or("en"), key)
+}
+
+fn lookup(lang: &str, key: &'static str) -> &'static str {
+	let find = |lang: &str| TABLE.iter().find(|(l, k, _)| *l == lang && *k == key).map(|(_, _, text)| *text);
+	find(lang).or_else(|| find("en")).unwrap_or(key)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn lookups() {
+		assert_eq!(lookup("en", "warning"), "Warning");
+		assert_eq!(lookup("de", "warning"), "Warnung");
+		assert_eq!(lookup("xx", "warning"), "Warning");
+		assert_eq!(lookup("de", "no_such_key"), "no_such_key");
+		// Every language has every key
+		for (lang, _, _) in TABLE {
+			for (_, key, _) in TABLE.iter().filter(|(l, _, _)| *l == "en") {
+				assert!(TABLE.iter().any(|(l, k, _)| l == lang && k == key), "{lang} is missing {key}");
+			}
+		}
+	}
+	
+}
@@ -0,0 +1,80 @@
+//! Remembers the last connected device, so the next run can try it before searching
+
+use std::path::{Path, PathBuf};
+use log::debug;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LastDevice {
+	pub address: String,
+	pub name: Option<String>,
+}
+
+impl LastDevice {
+	
+	fn to_json(&self) -> String {
+		jzon::object! {
+			address: self.address.as_str(),
+			name: self.name.as_deref(),
+		}.to_string()
+	}
+	
+	fn from_json(text: &str) -> Result<Self, anyhow::Error> {
+		let json = jzon::parse(text)?;
+		let address = json["address"].as_str().ok_or(anyhow::Error::msg("No address"))?.to_string();
+		Ok(Self { address, name: json["name"].as_str().map(str::to_string) })
+	}
+	
+}
+
+/// `$XDG_STATE_HOME/bluecolor/last_device.json`, or under `~/.local/state` without it
+pub fn path() -> Option<PathBuf> {
+	let state = match std::env::var_os("XDG_STATE_HOME") {
+		Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+		_ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+	};
+	Some(state.join("bluecolor/last_device.json"))
+}
+
+/// `None` if there isn't one (or it can't be read, which is just as good)
+pub fn load() -> Option<LastDevice> {
+	load_from(&path()?)
+}
+
+pub fn save(device: &LastDevice) -> Result<(), anyhow::Error> {
+	save_to(&path().ok_or(anyhow::Error::msg("No state directory"))?, device)
+}
+
+fn load_from(path: &Path) -> Option<LastDevice> {
+	let text = std::fs::read_to_string(path).ok()?;
+	LastDevice::from_json(&text)
+		.map_err(|e| debug!("Ignoring {}: {e}", path.display()))
+		.ok()
+}
+
+fn save_to(path: &Path, device: &LastDevice) -> Result<(), anyhow::Error> {
+	if let Some(dir) = path.parent() {
+		std::fs::create_dir_all(dir)?;
+	}
+	std::fs::write(path, device.to_json())?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn round_trip() {
+		let path = std::env::temp_dir().join(format!("bluecolor-test-{}/last_device.json", std::process::id()));
+		assert_eq!(load_from(&path), None);
+		
+		let device = LastDevice { address: "DC:8E:95:66:CD:B8".to_string(), name: Some("LS170002377".to_string()) };
+		save_to(&path, &device).unwrap();
+		assert_eq!(load_from(&path), Some(device));
+		
+		std::fs::write(&path, "{}").unwrap();
+		assert_eq!(load_from(&path), None);
+		std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+	}
+	
+}
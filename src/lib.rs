@@ -0,0 +1,32 @@
+//! Talk to Linshang LS170 colorimeters (and likely the LS171) over Bluetooth LE.
+//!
+//! Connect with [BleTransport::connect], run [device_loop] and send it [Command]s through the
+//! [DeviceHandle] from [device_channel]. Everything the device says comes back as [Event]s,
+//! which the printers in [output] can format the same way the command line tool does.
+//! [ColorReader] wraps all that in a few async methods.
+
+pub mod color_math;
+pub mod device;
+pub mod i18n;
+pub mod last_device;
+pub mod log_file;
+pub mod output;
+pub mod protocol;
+pub mod reader;
+pub mod rotating_file;
+pub mod script;
+pub mod timing;
+#[cfg(unix)]
+pub mod unix_socket;
+pub mod webhook;
+pub mod writer;
+
+#[cfg(test)]
+mod testing;
+
+#[macro_use]
+extern crate lazy_static;
+
+pub use device::{device_channel, device_loop, BleTransport, Command, DeviceConfig, DeviceHandle, Event, Transport};
+pub use output::ScanResult;
+pub use reader::ColorReader;
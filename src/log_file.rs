@@ -0,0 +1,44 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Log output appended to a file, and copied to stderr unless `only`
+#[derive(Clone)]
+pub struct LogFile {
+	path: PathBuf,
+	file: Arc<Mutex<File>>,
+	only: bool,
+}
+
+impl LogFile {
+	
+	pub fn open(path: impl AsRef<Path>, only: bool) -> Result<Self, anyhow::Error> {
+		let path = path.as_ref().to_path_buf();
+		let file = Arc::new(Mutex::new(Self::open_file(&path)?));
+		Ok(Self { path, file, only })
+	}
+	
+	/// Start writing to the path anew, e.g. after logrotate moved the file away
+	pub fn reopen(&self) -> Result<(), anyhow::Error> {
+		*self.file.lock().unwrap() = Self::open_file(&self.path)?;
+		Ok(())
+	}
+	
+	fn open_file(path: &Path) -> Result<File, anyhow::Error> {
+		OpenOptions::new().create(true).append(true).open(path)
+			.map_err(|e| anyhow::Error::msg(format!("Failed to open log file {}: {e}", path.display())))
+	}
+	
+}
+
+impl Write for LogFile {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		if !self.only { std::io::stderr().write_all(buf)?; }
+		self.file.lock().unwrap().write_all(buf)?;
+		Ok(buf.len())
+	}
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.file.lock().unwrap().flush()
+	}
+}
@@ -1,30 +1,34 @@
-#![feature(async_closure)]
-
-mod output;
-
-#[macro_use]
-extern crate lazy_static;
-
-use std::collections::VecDeque;
-use std::io::{BufRead, Cursor, Read};
+use std::io::IsTerminal;
 use std::str::FromStr;
-use std::sync::{Arc};
+use std::path::Path;
+use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 use btleplug::api::{BDAddr, Central, Manager as _, Peripheral as _, PeripheralProperties, ScanFilter};
 use btleplug::api::CentralEvent::DeviceDiscovered;
-use btleplug::api::WriteType::WithoutResponse;
-use btleplug::platform::{Manager, Peripheral};
-use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
-use clap::Parser;
-use env_logger::Env;
+use btleplug::platform::{Adapter, Manager, Peripheral};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap::builder::PossibleValuesParser;
+use env_logger::{Env, Target};
 use log::{debug, error, info, trace, warn};
-use tokio::sync::Mutex;
+use tokio::sync::mpsc;
 use tokio_stream::{StreamExt, StreamMap};
-use uuid::Uuid;
-use crate::output::{JSONPrinter, OutputFormat, OutputPrinter, ScanResult, TextPrinter, Triple};
+use bluecolor::color_math::is_monotone_gradient;
+use bluecolor::device::{device_channel, device_loop, BleTransport, Command, DeviceConfig, Event, HEARTBEAT_INTERVAL};
+use bluecolor::i18n;
+use bluecolor::last_device::{self, LastDevice};
+use bluecolor::log_file::LogFile;
+use bluecolor::output::{CsvPrinter, Destination, ExportFormat, DeviceListing, EventFilter, EVENT_NAMES, JSONPrinter, JsonStyle, LinePrinter, Location, Output, OutputFormat, OutputPrinter, ScanField, ScanResult, Sinks, SinkSpec, TextPrinter, TextStyle};
+use bluecolor::protocol::{NOTIF_SVC_ID, WRITE_SVC_ID};
+use bluecolor::rotating_file::{FileSize, RotatingFile};
+use bluecolor::script::{parse_script, ScriptOnError, ScriptStep};
+use bluecolor::timing::TimingLog;
+#[cfg(unix)]
+use bluecolor::unix_socket::{self, Broadcaster};
+use bluecolor::webhook;
+use bluecolor::writer::LineWriter;
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
@@ -33,18 +37,106 @@ struct Args {
 	#[arg(short, long)]
 	device: Option<String>,
 	
-	/// Output format (text, json)
+	/// Output format (text, json, line, csv)
 	#[arg(short, long, default_value = "text")]
 	format: OutputFormat,
 	
-	/// Log level (error, warn, info, debug, trace)
+	/// Where to send output, as format:destination (stdout or a file to append to), e.g. json:scans.jsonl. Can be repeated; replaces --format
+	#[arg(long)]
+	output: Vec<SinkSpec>,
+	
+	/// Also append JSON lines to this file, whatever the stdout format (same as --output json:PATH alongside --format)
+	#[arg(long)]
+	output_file: Option<String>,
+	
+	/// Start output files anew before they grow past this size (e.g. 10M), renaming the old one to FILE.1 (and FILE.1 to FILE.2, and so on). Also done on SIGHUP
+	#[arg(long)]
+	rotate_size: Option<FileSize>,
+	
+	/// How many rotated output files to keep
+	#[arg(long, default_value_t = 5)]
+	rotate_keep: usize,
+	
+	/// Leave the header row out of CSV output, e.g. when appending to a file that has one from an earlier run
+	#[arg(long)]
+	no_csv_header: bool,
+	
+	/// Write the CSV header row to this file (unless it exists already) instead of before the rows. Implies --no-csv-header
+	#[arg(long)]
+	csv_header_file: Option<String>,
+	
+	/// Write every scan of the session to this CSV file on exit (all color spaces and hex)
+	#[arg(long)]
+	export_csv: Option<String>,
+	
+	/// Write every scan of the session to a new file in this directory, named after the time it started, when it ends (unless interrupted with Ctrl-C or SIGTERM, or without scans)
+	#[arg(long)]
+	auto_export_dir: Option<String>,
+	
+	/// Format of the --auto-export-dir file: json or csv (CGATS is not supported)
+	#[arg(long, default_value = "csv")]
+	auto_export_format: ExportFormat,
+	
+	/// Only print these kinds of output on stdout, by their JSON names, e.g. scan,error. Logging is not affected.
+	#[arg(long, value_delimiter = ',', value_parser = PossibleValuesParser::new(EVENT_NAMES))]
+	events: Option<Vec<String>>,
+	
+	/// Language of text output (en, de)
+	#[arg(long, default_value = "en")]
+	lang: String,
+	
+	/// Text layout (full, compact, plain)
+	#[arg(long, default_value = "full")]
+	text_style: TextStyle,
+	
+	/// JSON line shape (wrapped, typed)
+	#[arg(long, default_value = "wrapped")]
+	json_style: JsonStyle,
+	
+	/// Print JSON color triples as objects with named components instead of arrays
+	#[arg(long)]
+	named_components: bool,
+	
+	/// Comma-separated scan fields to print (lab, luv, lch, yxy, rgb, rgb_f, hex, gamut, rate). JSON and CSV always have the scan's id as well, and JSON has hex along with rgb
+	#[arg(long, value_delimiter = ',', default_value = "lab,luv,lch,yxy,rgb")]
+	fields: Vec<ScanField>,
+	
+	/// Never color text output (also the case with NO_COLOR set, or when stdout is not a terminal)
+	#[arg(long)]
+	no_color: bool,
+	
+	/// Don't print hints in text output (they're also left out when stdout is not a terminal)
+	#[arg(short, long)]
+	quiet: bool,
+	
+	/// Only print scan results (line format)
 	#[arg(long)]
-	log_level: Option<log::LevelFilter>,
+	scans_only: bool,
+	
+	/// Log level (error, warn, info, debug, trace), optionally per module (e.g. info,btleplug=trace)
+	#[arg(long)]
+	log_level: Option<String>,
+	
+	/// Append log output to this file as well as stderr. Reopened on SIGHUP, for logrotate.
+	#[arg(long)]
+	log_file: Option<String>,
+	
+	/// Log only to --log-file, not stderr
+	#[arg(long, requires = "log_file")]
+	log_file_only: bool,
 	
 	/// Timeout to find the device, in seconds
 	#[arg(long, default_value_t = 5.0)]
 	scan_timeout: f32,
 	
+	/// Don't try the last used device before searching, nor remember this one
+	#[arg(long)]
+	no_cache: bool,
+	
+	/// Without --device, also pick a device that advertises only one of the two services (the characteristics are still checked after connecting)
+	#[arg(long)]
+	connect_anyway: bool,
+	
 	/// Get battery level and SN on launch.
 	#[arg(short, long)]
 	get_status: bool,
@@ -57,196 +149,613 @@ struct Args {
 	#[arg(short, long)]
 	scan: bool,
 	
-}
-
-lazy_static! {
+	/// Download the scans stored on the device on launch, using this frame (hex, including checksum) as the command
+	#[arg(long)]
+	history_command: Option<String>,
 	
-	/// Command destination service
-	static ref WRITE_SVC_ID: Uuid = Uuid::parse_str("0000ffe5-0000-1000-8000-00805f9b34fb").unwrap();
-	/// Command destination characteristic
-	static ref WRITE_CHR_ID: Uuid = Uuid::parse_str("0000ffe9-0000-1000-8000-00805f9b34fb").unwrap();
+	/// Tag live scans with this location (lat,lon)
+	#[arg(long)]
+	location: Option<Location>,
 	
-	/// Notification service
-	static ref NOTIF_SVC_ID: Uuid = Uuid::parse_str("0000ffe0-0000-1000-8000-00805f9b34fb").unwrap();
-	/// Notification characteristic
-	static ref NOTIF_CHR_ID: Uuid = Uuid::parse_str("0000ffe4-0000-1000-8000-00805f9b34fb").unwrap();
+	/// Read the location (lat,lon) for each live scan from this file, e.g. kept up to date by a GPS script. Falls back to --location.
+	#[arg(long)]
+	location_file: Option<String>,
 	
-	/// The command to trigger a color scan (results sent as AB44... notification)
-	static ref SCAN_CMD: Vec<u8> = hex::decode("AB440000000036001864").unwrap();
+	/// Send this raw frame (hex, including checksum) after the other launch commands, e.g. to reset the device
+	#[arg(long)]
+	reset_command: Option<String>,
 	
-	/// The command to trigger a calibration (result: AB202E00020000002DF4)
-	static ref CALIBRATE_CMD: Vec<u8> = hex::decode("AB202E000200904F").unwrap();
+	/// Run commands (status, calibrate, scan, sleep <secs>) from this file, one per line, after the launch commands
+	#[arg(long)]
+	script: Option<String>,
 	
-	/// The command to request battery level
-	static ref BATTERY_CMD: Vec<u8> = hex::decode("AB200B0002009B43").unwrap();
+	/// What to do when a script command gets no answer or an unusable one (abort: disconnect and exit with an error, continue)
+	#[arg(long, default_value = "abort")]
+	script_on_error: ScriptOnError,
 	
-	/// The command to request device info
-	static ref INFO_CMD: Vec<u8> = hex::decode("AB400000000014004504").unwrap();
+	/// Disconnect and exit once the script has finished
+	#[arg(long)]
+	script_exit: bool,
 	
+	/// Print unrecognized AB messages as "unknown" updates (hex) instead of warnings
+	#[arg(long)]
+	emit_unknown: bool,
+	
+	/// Include the raw bytes of each scan message in JSON output (as "raw", hex), e.g. for bug reports
+	#[arg(long)]
+	raw_scans: bool,
+	
+	/// Report whether the session's scans form a gradient when it ends
+	#[arg(long)]
+	detect_gradient: bool,
+	
+	/// Give up when more than this many writes fail within --write-error-window-secs
+	#[arg(long, default_value_t = 3)]
+	max_write_errors: usize,
+	
+	/// Window for counting failed writes, in seconds
+	#[arg(long, default_value_t = 30)]
+	write_error_window_secs: u64,
+	
+	/// Give up on a command the device doesn't answer within this many seconds (0 to wait forever)
+	#[arg(long, default_value_t = 10.0)]
+	command_timeout: f32,
+	
+	/// Send a scan command again if no scan arrives within this many seconds
+	#[arg(long, default_value_t = 5.0)]
+	scan_retry_timeout: f32,
+	
+	/// How many times to send a scan command again before giving up (0 to treat scans like other commands)
+	#[arg(long, default_value_t = 2)]
+	scan_retry_count: usize,
+	
+	/// Warn when a scan is sent more than this many minutes after the last calibration, or without one since connecting (0 to never warn)
+	#[arg(long, default_value_t = 0.0)]
+	calibration_max_age: f32,
+	
+	/// Write a CSV trace of every command write and notification to this file
+	#[arg(long)]
+	timing_log: Option<String>,
+	
+	/// Send every event as a JSON line to each client of a Unix socket at this path (removed on exit). Linux and macOS only
+	#[arg(long)]
+	unix_socket: Option<String>,
+	
+	/// Listen for HTTP requests on this port: POST /trigger to scan (optionally with {"label":"..."}), GET /health
+	#[arg(long)]
+	webhook_port: Option<u16>,
+	
+	/// Address to listen on for --webhook-port. The webhook has no authentication, so only use 0.0.0.0 on a trusted network
+	#[arg(long, default_value = "127.0.0.1")]
+	webhook_host: String,
+	
+	/// Give up if the device connection seems stuck for this long, in seconds (0 to never give up).
+	/// Must be longer than the device loop's heartbeat interval of 10 seconds.
+	#[arg(long, default_value_t = 60, value_parser = parse_watchdog_timeout)]
+	watchdog_timeout: u64,
+	
+	/// Print a heartbeat (uptime and seconds since the last scan) every this many seconds, for supervisors (0 to never)
+	#[arg(long, default_value_t = 0)]
+	heartbeat: u64,
+	
+	/// List nearby devices (for the duration of --scan-timeout) and exit
+	#[arg(short, long)]
+	list: bool,
+	
+	/// List Bluetooth adapters and exit
+	#[arg(long)]
+	list_adapters: bool,
+	
+	#[command(subcommand)]
+	tool: Option<Tool>,
+	
+}
+
+// Things to print instead of connecting, for packaging (a doc comment here would become the --help about text)
+#[derive(Subcommand, Debug, Clone)]
+enum Tool {
+	/// Print shell completions (bash, zsh, fish, elvish, powershell)
+	Completions {
+		shell: clap_complete::Shell,
+	},
+	/// Print the man page
+	Manpage,
+}
+
+/// How often the watchdog looks at the last heartbeat
+const WATCHDOG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Seconds, 0 or more than [HEARTBEAT_INTERVAL], as a healthy connection is only heard from that often
+fn parse_watchdog_timeout(s: &str) -> Result<u64, String> {
+	match s.parse::<u64>() {
+		Ok(secs) if secs == 0 || secs > HEARTBEAT_INTERVAL.as_secs() => Ok(secs),
+		Ok(_) => Err(format!("Must be 0 or more than the {} second heartbeat interval", HEARTBEAT_INTERVAL.as_secs())),
+		Err(e) => Err(e.to_string()),
+	}
 }
 
+/// How long to try the last used device before searching after all
+const CACHED_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
 	let args = Args::parse();
 	
+	match args.tool {
+		Some(Tool::Completions { shell }) => {
+			clap_complete::generate(shell, &mut Args::command(), "bluecolor", &mut std::io::stdout());
+			return Ok(());
+		},
+		Some(Tool::Manpage) => {
+			clap_mangen::Man::new(Args::command()).render(&mut std::io::stdout())?;
+			return Ok(());
+		},
+		None => {},
+	}
+	
 	let mut log_b = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
-	if let Some(ll) = args.log_level { log_b.filter_level(ll); };
+	if let Some(ll) = &args.log_level { log_b.parse_filters(ll); };
+	let log_file = args.log_file.as_deref().map(|path| LogFile::open(path, args.log_file_only)).transpose()?;
+	if let Some(log_file) = &log_file { log_b.target(Target::Pipe(Box::new(log_file.clone()))); }
 	log_b.init();
 	
-	let printer: Box<dyn OutputPrinter> = match args.format {
-		OutputFormat::Text => Box::new(TextPrinter {}),
-		OutputFormat::JSON => Box::new(JSONPrinter {}),
+	i18n::set_lang(&args.lang)?;
+	
+	let mut specs = if args.output.is_empty() {
+		vec![SinkSpec { format: args.format, dest: Destination::Stdout }]
+	} else {
+		args.output.clone()
+	};
+	if let Some(path) = &args.output_file {
+		specs.push(SinkSpec { format: OutputFormat::Json, dest: Destination::File(path.clone()) });
+	}
+	if let Some(path) = &args.csv_header_file {
+		if !std::path::Path::new(path).exists() {
+			std::fs::write(path, CsvPrinter::new(args.fields.clone()).header() + "\n")
+				.map_err(|e| anyhow::Error::msg(format!("Failed to write CSV header to {path}: {e}")))?;
+		}
+	}
+	#[cfg(not(unix))]
+	if args.unix_socket.is_some() {
+		return Err(anyhow::Error::msg("--unix-socket is only supported on Linux and macOS"));
+	}
+	let mut rotating = Vec::new();
+	// Only added to for the Unix socket
+	#[cfg_attr(not(unix), allow(unused_mut))]
+	let mut outputs: Vec<Output> = specs.iter().map(|spec| open_sink(&args, spec, &mut rotating)).collect::<Result<_, _>>()?;
+	
+	#[cfg(unix)]
+	if log_file.is_some() || !rotating.is_empty() {
+		let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+		tokio::spawn(async move {
+			while hangup.recv().await.is_some() {
+				if let Some(log_file) = &log_file {
+					match log_file.reopen() {
+						Ok(()) => info!("Reopened log file"),
+						Err(e) => error!("{e}"),
+					}
+				}
+				for file in &rotating {
+					if let Err(e) = file.rotate() {
+						error!("Failed to rotate output file: {e}");
+					}
+				}
+			}
+		});
+	}
+	
+	#[cfg(unix)]
+	let _socket_file = match &args.unix_socket {
+		Some(path) => {
+			let (listener, file) = unix_socket::bind(path)?;
+			let broadcaster = Broadcaster::new();
+			let lines = broadcaster.sender();
+			tokio::spawn(async move {
+				if let Err(e) = unix_socket::serve(listener, lines).await {
+					error!("Socket server failed: {e}");
+				}
+			});
+			outputs.push(Output { printer: make_printer(&args, OutputFormat::Json, false), writer: LineWriter::spawn(broadcaster), filter: EventFilter::all() });
+			Some(file)
+		},
+		None => None,
 	};
+	let printer = Sinks(outputs);
 	
-	let manager = Manager::new().await?;
+	// One Manager for the whole run
+	let adapters = get_adapters().await?;
 	
-	let found = tokio::time::timeout(
-		Duration::from_secs_f32(args.scan_timeout),
-		find_device(manager, args.clone()),
-	).await??;
-	let (device, props) = found.ok_or(anyhow::Error::msg("No device found"))?;
-	let device = Arc::new(device);
-	if args.device.is_none() {
-		info!("Selected device: {} {:?}", device.address(), props.local_name);
+	if args.list_adapters {
+		let mut infos = Vec::new();
+		for ad in &adapters {
+			infos.push(ad.adapter_info().await?);
+		}
+		printer.print_misc("adapters", infos.into());
+		return Ok(());
 	}
 	
-	let connected = device.is_connected().await?;
-	debug!("Connected = {connected}");
-	if !connected {
-		debug!("Connecting");
-		device.connect().await?;
+	if args.list {
+		let devices = list_devices(&adapters, Duration::from_secs_f32(args.scan_timeout)).await?;
+		printer.print_devices(&devices);
+		return Ok(());
 	}
-	debug!("Connected");
-	
-	device.discover_services().await?;
-	let chars = device.characteristics();
-	
-	trace!("chars = {chars:?}");
-	
-	let notif_char = chars.iter().find(|c| c.uuid == *NOTIF_CHR_ID).unwrap().clone();
-	trace!("notif_char = {notif_char:?}");
-	device.subscribe(&notif_char).await?;
-	
-	let write_char = chars.iter().find(|c| c.uuid == *WRITE_CHR_ID).unwrap().clone();
-	let write_char_clone = write_char.clone();
-	trace!("write_char = {write_char:?}");
-	
-	let waiting = Arc::new(AtomicBool::new(false));
-	let commands = Arc::new(Mutex::new(VecDeque::<Vec<u8>>::new()));
-	
-	let waiting_arc = waiting.clone();
-	let device_arc = device.clone();
-	let commands_arc = commands.clone();
-	let enqueue_command = async move |cmd: &Vec<u8>| {
-		let mut commands = commands_arc.lock().await;
-		if commands.is_empty() && !waiting_arc.load(Relaxed) {
-			debug!("write immediate command: {:x?}", cmd);
-			device_arc.write(&write_char, cmd, WithoutResponse).await?;
-			waiting_arc.store(true, Relaxed);
-		} else {
-			commands.push_back(cmd.clone());
+	
+	let history_cmd = args.history_command.as_deref().map(hex::decode).transpose()
+		.map_err(|e| anyhow::Error::msg(format!("Invalid --history-command: {e}")))?;
+	let reset_cmd = args.reset_command.as_deref().map(hex::decode).transpose()
+		.map_err(|e| anyhow::Error::msg(format!("Invalid --reset-command: {e}")))?;
+	
+	let script = args.script.as_deref().map(|path| parse_script(&std::fs::read_to_string(path)?)).transpose()?;
+	
+	let webhook = match args.webhook_port {
+		Some(port) => Some(webhook::bind(&args.webhook_host, port).await?),
+		None => None,
+	};
+	
+	let cached = match (&args.device, args.no_cache) {
+		(None, false) => last_device::load(),
+		_ => None,
+	};
+	let cached = match cached {
+		Some(last) => connect_cached(&adapters, &last).await,
+		None => None,
+	};
+	let (transport, last) = match cached {
+		Some(found) => found,
+		None => {
+			let found = find_device(&adapters, args.clone()).await?;
+			let (device, props) = found.ok_or(anyhow::Error::msg("No device found"))?;
+			if args.device.is_none() {
+				info!("Selected device: {} {:?}", device.address(), props.local_name);
+			}
+			let last = LastDevice { address: props.address.to_string(), name: props.local_name };
+			// find_device took over Ctrl-C, so it has to be listened for until the handler below is set up
+			let transport = tokio::select! {
+				transport = BleTransport::connect(device) => transport?,
+				_ = shutdown_signal() => return Err(anyhow::Error::msg("Interrupted")),
+			};
+			(transport, last)
+		},
+	};
+	if !args.no_cache {
+		if let Err(e) = last_device::save(&last) {
+			warn!("Failed to remember the device: {e}");
 		}
-		Ok::<(), anyhow::Error>(())
+	}
+	
+	let config = DeviceConfig {
+		emit_unknown: args.emit_unknown,
+		keep_raw: args.raw_scans,
+		timing: args.timing_log.as_deref().map(TimingLog::create).transpose()?,
+		max_write_errors: args.max_write_errors,
+		write_error_window: Duration::from_secs(args.write_error_window_secs),
+		command_timeout: Some(Duration::from_secs_f32(args.command_timeout)).filter(|t| !t.is_zero()),
+		scan_retries: args.scan_retry_count,
+		scan_retry_timeout: Duration::from_secs_f32(args.scan_retry_timeout),
+		calibration_max_age: Some(Duration::from_secs_f32(args.calibration_max_age * 60.0)).filter(|t| !t.is_zero()),
 	};
+	let (handle, commands) = device_channel();
+	let (events_tx, mut events) = mpsc::unbounded_channel();
+	let device_task = tokio::spawn(device_loop(transport, config, commands, events_tx));
+	
+	if let Some(listener) = webhook {
+		let handle = handle.clone();
+		tokio::spawn(async move {
+			if let Err(e) = webhook::serve(listener, handle).await {
+				error!("Webhook server failed: {e}");
+			}
+		});
+	}
 	
-	let commands_arc = commands.clone();
-	let waiting_arc = waiting.clone();
-	let device_arc = device.clone();
-	let mut notif_stream = device.notifications().await?;
-	let notif = tokio::spawn(async move {
-		let mut count: usize = 0;
-		while let Some(v) = notif_stream.next().await {
-			let msg = v.value;
-			debug!("Received: {:x?}", msg);
-			let [a, b, c] = msg[0..3] else {
-				error!("Message too short: {:x?}", msg);
-				continue;
+	// Updated by the printing task, checked by the watchdog
+	let last_heartbeat = Arc::new(std::sync::Mutex::new(Instant::now()));
+	if args.watchdog_timeout > 0 {
+		let timeout = Duration::from_secs(args.watchdog_timeout);
+		let device_abort = device_task.abort_handle();
+		let last_heartbeat = last_heartbeat.clone();
+		tokio::spawn(async move {
+			loop {
+				tokio::time::sleep(WATCHDOG_CHECK_INTERVAL).await;
+				if last_heartbeat.lock().unwrap().elapsed() > timeout {
+					error!("Device connection stuck for over {} seconds, giving up", timeout.as_secs());
+					device_abort.abort();
+					break;
+				}
+			}
+		});
+	}
+	
+	// Disconnect cleanly so everything gets written out, unless asked twice
+	let interrupted = Arc::new(AtomicBool::new(false));
+	{
+		let handle = handle.clone();
+		let interrupted = interrupted.clone();
+		tokio::spawn(async move {
+			shutdown_signal().await;
+			interrupted.store(true, Relaxed);
+			info!("Disconnecting (again to quit right away)");
+			handle.send(Command::Disconnect).ok();
+			shutdown_signal().await;
+			std::process::exit(130);
+		});
+	}
+	
+	let detect_gradient = args.detect_gradient;
+	let export_csv = args.export_csv.clone();
+	let auto_export = args.auto_export_dir.clone().map(|dir| (dir, args.auto_export_format));
+	let started_at = SystemTime::now();
+	let (location, location_file) = (args.location, args.location_file.clone());
+	let heartbeat = Some(Duration::from_secs(args.heartbeat)).filter(|d| !d.is_zero());
+	let started = Instant::now();
+	let printing = tokio::spawn(async move {
+		let mut session: Vec<ScanResult> = Vec::new();
+		let mut exported: Vec<ScanResult> = Vec::new();
+		let mut last_scan: Option<Instant> = None;
+		let mut ticks = heartbeat.map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+		loop {
+			let event = tokio::select! {
+				event = events.recv() => match event {
+					Some(event) => event,
+					None => break,
+				},
+				_ = async { ticks.as_mut().unwrap().tick().await }, if ticks.is_some() => {
+					printer.print_misc("heartbeat", jzon::object! {
+						uptime_s: started.elapsed().as_secs(),
+						last_scan_age_s: last_scan.map(|at| at.elapsed().as_secs()),
+					});
+					continue;
+				},
 			};
-			if a != 0xAB {
-				warn!("Unknown message: {:x?}", msg);
+			if let Event::Heartbeat = event {
+				*last_heartbeat.lock().unwrap() = Instant::now();
 				continue;
 			}
-			
-			if b == 0x44 {
-				debug!("Is color scan result (AB44)");
-				
-				count += 1;
-				let idx = count;
-				
-				let mut cur = Cursor::new(msg);
-				cur.consume(8);
-				
-				let mut read_floats = || {
-					Triple((0..3).map(|_| {
-						(cur.read_i16::<LittleEndian>().unwrap() as f32) / 100.0
-					}).collect::<Vec<f32>>().try_into().unwrap())
-				};
-				let lab = read_floats();
-				let luv = read_floats();
-				let lch = read_floats();
-				let yxy = read_floats();
-				
-				// Some arbitrary CMYK here. Useless in practice.
-				cur.consume(4);
-				
-				let mut rgb_arr: [u8; 3] = [0; 3];
-				cur.read_exact(&mut rgb_arr).unwrap();
-				let rgb = Triple(rgb_arr);
-				
-				let result = ScanResult { idx, lab, luv, lch, yxy, rgb };
-				
-				debug!("result = {result:?}");
-				printer.print_result(result);
-			} else if (b, c) == (0x20, 0x2E) {
-				debug!("Is calibration response (AB202E)");
-				printer.print_misc("calibrated", true.into());
-			} else if (b, c) == (0x20, 0x0B) {
-				debug!("Is power level response (AB200B)");
-				let level = LittleEndian::read_i16(&msg[6..8]);
-				printer.print_misc("power_level", level.into());
-			} else if (b, c) == (0x40, 0x00) {
-				debug!("Is device info response (AB4000)");
-				let device_info: Vec<i16> = (10..25).map(|idx| LittleEndian::read_i16(&msg[idx..(idx + 2)])).collect();
-				printer.print_misc("device_info", device_info.into());
-			} else {
-				warn!("Unknown message: {:x?}", msg);
+			let event = match event {
+				Event::Scan(result) if !result.historical => Event::Scan(ScanResult {
+					location: location_file.as_deref().and_then(read_location).or(location),
+					..result
+				}),
+				event => event,
+			};
+			printer.print_event(&event);
+			if let Event::Scan(result) = event {
+				if export_csv.is_some() || auto_export.is_some() { exported.push(result.clone()); }
+				if result.historical { continue; }
+				last_scan = Some(Instant::now());
+				if detect_gradient { session.push(result); }
 			}
-			
-			let mut commands = commands_arc.lock().await;
-			if let Some(cmd) = commands.pop_front() {
-				debug!("write queued command: {:x?}", cmd);
-				device_arc.write(&write_char_clone, &cmd, WithoutResponse).await.unwrap();
-				waiting_arc.store(true, Relaxed);
-			} else {
-				waiting_arc.store(false, Relaxed);
+		}
+		if detect_gradient {
+			printer.print_misc("gradient", is_monotone_gradient(&session).into());
+		}
+		if let Some(path) = export_csv {
+			match write_csv(&path, &exported) {
+				Ok(()) => info!("Exported {} scan(s) to {path}", exported.len()),
+				Err(e) => error!("Failed to export scans to {path}: {e}"),
 			}
 		}
+		match auto_export {
+			Some(_) if interrupted.load(Relaxed) => info!("Interrupted, not exporting the session"),
+			Some(_) if exported.is_empty() => debug!("No scans, not exporting the session"),
+			Some((dir, format)) => {
+				// ISO 8601 basic format, as colons don't make for portable file names
+				let stamp = humantime::format_rfc3339_seconds(started_at).to_string().replace(['-', ':'], "");
+				let path = Path::new(&dir).join(format!("{stamp}.{}", format.extension()));
+				let res = match format {
+					ExportFormat::Csv => write_csv(&path, &exported),
+					ExportFormat::Json => write_json(&path, &exported),
+				};
+				match res {
+					Ok(()) => info!("Session exported to {}", path.display()),
+					Err(e) => error!("Failed to export the session to {}: {e}", path.display()),
+				}
+			},
+			None => {},
+		}
 	});
 	
+	// If the loop is gone already, awaiting it below says why
 	if args.get_status {
 		debug!("Writing status commands");
-		enqueue_command(&INFO_CMD).await?;
-		enqueue_command(&BATTERY_CMD).await?;
+		handle.send(Command::Status).ok();
+	}
+	
+	if let Some(cmd) = history_cmd {
+		debug!("Writing history download command");
+		handle.send(Command::DownloadHistory(cmd)).ok();
 	}
 	
 	if args.calibrate {
 		debug!("Writing calibrate command");
-		enqueue_command(&CALIBRATE_CMD).await?;
+		handle.send(Command::Calibrate).ok();
 	}
 	if args.scan {
 		debug!("Writing scan command");
-		enqueue_command(&SCAN_CMD).await?;
+		handle.send(Command::Scan).ok();
+	}
+	// Each step waits for its answer, so a failed one can stop the rest
+	let mut script_error = None;
+	let scripted = script.is_some();
+	if let Some(steps) = script {
+		for step in steps {
+			debug!("Script step: {step:?}");
+			let res = match step {
+				ScriptStep::Status => handle.run(Command::Status).await,
+				ScriptStep::Calibrate => handle.run(Command::Calibrate).await,
+				ScriptStep::Scan => handle.run(Command::Scan).await,
+				ScriptStep::Sleep(duration) => {
+					handle.wait_idle().await;
+					tokio::time::sleep(duration).await;
+					Ok(())
+				},
+			};
+			if let Err(e) = res {
+				match args.script_on_error {
+					ScriptOnError::Abort => {
+						script_error = Some(anyhow::Error::msg(format!("Script aborted: {e}")));
+						break;
+					},
+					ScriptOnError::Continue => error!("Script command failed: {e}"),
+				}
+			}
+		}
+	}
+	
+	// Nothing is known to answer this, so it goes last
+	if let Some(cmd) = reset_cmd.filter(|_| script_error.is_none()) {
+		info!("Sending reset command, the device will likely disconnect");
+		handle.send(Command::Raw(cmd)).ok();
 	}
 	
-	notif.await.unwrap();
+	if scripted && (script_error.is_some() || args.script_exit) {
+		handle.wait_idle().await;
+		debug!("Script done, disconnecting");
+		handle.send(Command::Disconnect).ok();
+	}
+	
+	// Whatever happened, the output is finished before returning
+	let res = match device_task.await {
+		Ok(res) => res,
+		Err(e) if e.is_cancelled() => Err(anyhow::Error::msg("Device connection stuck")),
+		Err(e) => Err(e.into()),
+	};
+	let res = res.and(printing.await.map_err(Into::into));
 	
+	match script_error {
+		Some(e) => res.and(Err(e)),
+		None => res,
+	}
+}
+
+/// Ctrl-C, or SIGTERM where there is one
+async fn shutdown_signal() {
+	#[cfg(unix)]
+	if let Ok(mut term) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+		tokio::select! {
+			_ = tokio::signal::ctrl_c() => {},
+			_ = term.recv() => {},
+		}
+		return;
+	}
+	tokio::signal::ctrl_c().await.ok();
+}
+
+/// Everything an export has, whatever --fields says
+const EXPORT_FIELDS: [ScanField; 6] = [ScanField::Lab, ScanField::Luv, ScanField::Lch, ScanField::Yxy, ScanField::Rgb, ScanField::Hex];
+
+/// The whole session as one CSV file, with a header
+fn write_csv(path: impl AsRef<Path>, scans: &[ScanResult]) -> Result<(), anyhow::Error> {
+	let printer = CsvPrinter::new(EXPORT_FIELDS.to_vec());
+	let mut text: String = scans.iter().filter_map(|res| printer.format_result(res)).map(|row| row + "\n").collect();
+	if scans.is_empty() {
+		text = printer.header() + "\n";
+	}
+	std::fs::write(path, text)?;
+	Ok(())
+}
+
+/// The whole session as JSON lines, the same as --format json prints scans
+fn write_json(path: impl AsRef<Path>, scans: &[ScanResult]) -> Result<(), anyhow::Error> {
+	let printer = JSONPrinter { fields: EXPORT_FIELDS.to_vec(), style: JsonStyle::Wrapped, named_components: false };
+	let text: String = scans.iter().filter_map(|res| printer.format_result(res)).map(|line| line + "\n").collect();
+	std::fs::write(path, text)?;
 	Ok(())
 }
 
-async fn find_device(manager: Manager, args: Args) -> Result<Option<(Peripheral, PeripheralProperties)>, anyhow::Error> {
+fn read_location(path: &str) -> Option<Location> {
+	let res = std::fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|text| text.trim().parse());
+	res.map_err(|e| warn!("Failed to read location from {path}: {e}")).ok()
+}
+
+/// Files opened with --rotate-size are added to `rotating`, for rotating on SIGHUP
+fn open_sink(args: &Args, spec: &SinkSpec, rotating: &mut Vec<RotatingFile>) -> Result<Output, anyhow::Error> {
+	let terminal = spec.dest == Destination::Stdout && std::io::stdout().is_terminal();
+	let printer = make_printer(args, spec.format, terminal);
+	let filter = match (&spec.dest, &args.events) {
+		(Destination::Stdout, Some(names)) => EventFilter::only(names.clone()),
+		_ => EventFilter::all(),
+	};
+	let writer = match (&spec.dest, args.rotate_size) {
+		(Destination::Stdout, _) => LineWriter::spawn(std::io::stdout()),
+		(Destination::File(path), Some(size)) => {
+			let file = RotatingFile::open(path, size.0, args.rotate_keep)?;
+			rotating.push(file.clone());
+			LineWriter::spawn(file)
+		},
+		(Destination::File(path), None) => {
+			let file = std::fs::OpenOptions::new().create(true).append(true).open(path)
+				.map_err(|e| anyhow::Error::msg(format!("Failed to open output file {path}: {e}")))?;
+			LineWriter::spawn(file)
+		},
+	};
+	Ok(Output { printer, writer, filter })
+}
+
+/// `terminal` is whether the output goes to one, for colors and hints
+fn make_printer(args: &Args, format: OutputFormat, terminal: bool) -> Box<dyn OutputPrinter> {
+	match format {
+		OutputFormat::Text => Box::new(TextPrinter {
+			fields: args.fields.clone(),
+			style: args.text_style,
+			// https://no-color.org
+			colors: args.text_style != TextStyle::Plain && terminal && !args.no_color
+				&& std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+			hints: !args.quiet && terminal,
+		}),
+		OutputFormat::Json => Box::new(JSONPrinter {
+			fields: args.fields.clone(),
+			style: args.json_style,
+			named_components: args.named_components,
+		}),
+		OutputFormat::Line => Box::new(LinePrinter { scans_only: args.scans_only }),
+		OutputFormat::Csv if args.no_csv_header || args.csv_header_file.is_some() => Box::new(CsvPrinter::without_header(args.fields.clone())),
+		OutputFormat::Csv => Box::new(CsvPrinter::new(args.fields.clone())),
+	}
+}
+
+/// Only called once Bluetooth is actually needed, as setting it up can take a while
+async fn get_adapters() -> Result<Vec<Adapter>, anyhow::Error> {
+	let adapters = Manager::new().await?.adapters().await?;
+	if adapters.is_empty() {
+		return Err(anyhow::Error::msg("No Bluetooth adapter found. Ensure a BT adapter is connected and the driver is loaded."));
+	}
+	Ok(adapters)
+}
+
+/// Connect straight to the last used device if an adapter still knows it, skipping discovery.
+/// `None` if that doesn't work out quickly, to search instead.
+async fn connect_cached(adapters: &[Adapter], last: &LastDevice) -> Option<(BleTransport, LastDevice)> {
+	info!("Trying the last used device {} {:?}", last.address, last.name);
+	let attempt = async {
+		let addr = BDAddr::from_str(&last.address)?;
+		for ad in adapters {
+			for p in ad.peripherals().await? {
+				if p.address() != addr { continue; }
+				return Ok(Some(BleTransport::connect(p).await?));
+			}
+		}
+		Ok::<_, anyhow::Error>(None)
+	};
+	match tokio::time::timeout(CACHED_CONNECT_TIMEOUT, attempt).await {
+		Ok(Ok(Some(transport))) => Some((transport, last.clone())),
+		Ok(Ok(None)) => {
+			debug!("Last used device is not known to any adapter, searching");
+			None
+		},
+		Ok(Err(e)) => {
+			warn!("Failed to connect to the last used device, searching: {e}");
+			None
+		},
+		Err(_) => {
+			warn!("Last used device didn't connect in time, searching");
+			None
+		},
+	}
+}
+
+async fn find_device(adapters: &[Adapter], args: Args) -> Result<Option<(Peripheral, PeripheralProperties)>, anyhow::Error> {
 	
 	// Scan all BT adapters (not actually tested with more than one)
-	let adapters = manager.adapters().await?;
 	let mut scans = StreamMap::new();
 	for (aidx, ad) in adapters.iter().enumerate() {
 		scans.insert(aidx, ad.events().await?);
@@ -255,25 +764,87 @@ async fn find_device(manager: Manager, args: Args) -> Result<Option<(Peripheral,
 	
 	let arg_addr = if let Some(str) = args.device { Some(BDAddr::from_str(&str)?) } else { None };
 	trace!("requested addr {arg_addr:?}");
-	while let Some((aidx, ev)) = scans.next().await {
-		trace!("event @{aidx} {ev:?}");
-		if let DeviceDiscovered(pid) = ev {
-			let ad = &adapters[aidx];
-			let p = ad.peripheral(&pid).await?;
-			if let Some(props) = p.properties().await? {
+	let search = async {
+		while let Some((aidx, ev)) = scans.next().await {
+			trace!("event @{aidx} {ev:?}");
+			if let DeviceDiscovered(pid) = ev {
+				let ad = &adapters[aidx];
+				let p = ad.peripheral(&pid).await?;
+				if let Some(props) = p.properties().await? {
+					let capable = props.services.contains(&WRITE_SVC_ID) && props.services.contains(&NOTIF_SVC_ID);
+					let partial = props.services.contains(&WRITE_SVC_ID) || props.services.contains(&NOTIF_SVC_ID);
+					debug!("device {} {:?} ({:?}), capable = {:?}", props.address, props.address_type, props.local_name, capable);
+					// Only check for address if passed. Just the 6 bytes, as the type (public or random)
+					// may be reported differently than wherever the user got the address from.
+					if let Some(addr) = arg_addr {
+						if props.address.into_inner() == addr.into_inner() {
+							if !capable { warn!("Device doesn't advertise the expected services, connecting anyway"); }
+							return Ok(Some((p, props)));
+						}
+					}
+					// Otherwise return first capable
+					else if capable {
+						return Ok(Some((p, props)));
+					}
+					// Some firmware under-reports services until connected
+					else if args.connect_anyway && partial {
+						warn!("Device {} advertises only some of the expected services, connecting anyway", props.address);
+						return Ok(Some((p, props)));
+					}
+				}
+			}
+		}
+		Ok::<_, anyhow::Error>(None)
+	};
+	
+	// Whatever the outcome, don't leave the adapters scanning
+	let found = tokio::select! {
+		found = tokio::time::timeout(Duration::from_secs_f32(args.scan_timeout), search) => found.unwrap_or(Ok(None)),
+		_ = tokio::signal::ctrl_c() => Err(anyhow::Error::msg("Interrupted")),
+	};
+	stop_scans(adapters).await;
+	found
+}
+
+async fn list_devices(adapters: &[Adapter], timeout: Duration) -> Result<Vec<DeviceListing>, anyhow::Error> {
+	
+	let mut scans = StreamMap::new();
+	for (aidx, ad) in adapters.iter().enumerate() {
+		scans.insert(aidx, ad.events().await?);
+		ad.start_scan(ScanFilter::default()).await?;
+	}
+	
+	let mut devices: Vec<DeviceListing> = Vec::new();
+	let collect = async {
+		while let Some((aidx, ev)) = scans.next().await {
+			trace!("event @{aidx} {ev:?}");
+			if let DeviceDiscovered(pid) = ev {
+				let p = adapters[aidx].peripheral(&pid).await?;
+				let Some(props) = p.properties().await? else { continue };
+				let address = props.address.to_string();
+				if devices.iter().any(|d| d.address == address) { continue; }
 				let capable = props.services.contains(&WRITE_SVC_ID) && props.services.contains(&NOTIF_SVC_ID);
 				debug!("device {} ({:?}), capable = {:?}", props.address, props.local_name, capable);
-				// Only check for address if passed
-				if let Some(addr) = arg_addr {
-					if props.address == addr { return Ok(Some((p, props))); };
-				}
-				// Otherwise return first capable
-				else if capable {
-					return Ok(Some((p, props)));
-				}
+				devices.push(DeviceListing { address, name: props.local_name, rssi: props.rssi, capable });
 			}
 		}
+		Ok::<(), anyhow::Error>(())
+	};
+	// Running out of time (or Ctrl-C) is the normal way to finish here
+	tokio::select! {
+		res = tokio::time::timeout(timeout, collect) => if let Ok(res) = res { res?; },
+		_ = tokio::signal::ctrl_c() => {},
 	}
+	stop_scans(adapters).await;
 	
-	Ok(None)
+	Ok(devices)
+}
+
+async fn stop_scans(adapters: &[Adapter]) {
+	for ad in adapters {
+		if let Err(e) = ad.stop_scan().await {
+			warn!("Failed to stop scan: {e}");
+		}
+	}
+	debug!("Stopped scanning");
 }
@@ -1,12 +1,23 @@
 use std::fmt::{Display, Formatter};
+use std::ops::{Add, Mul, Sub};
 use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
 use jzon::JsonValue;
+use uuid::Uuid;
+use crate::device::Event;
+use crate::i18n::t;
+use crate::color_math::{in_adobe_rgb, in_display_p3, in_srgb, yxy_to_xyz};
+use crate::writer::LineWriter;
 
 #[derive(Clone, Copy, Debug)]
 pub enum OutputFormat {
 	Text,
 	// TSV,
-	JSON,
+	Json,
+	Line,
+	Csv,
 }
 
 impl FromStr for OutputFormat {
@@ -15,71 +26,831 @@ impl FromStr for OutputFormat {
 		match &*s.to_ascii_lowercase() {
 			"text" => Ok(Self::Text),
 			// "tsv" => Ok(Self::TSV),
-			"json" => Ok(Self::JSON),
+			"json" => Ok(Self::Json),
+			"line" => Ok(Self::Line),
+			"csv" => Ok(Self::Csv),
 			_ => Err(format!("Unknown output format: {s}"))
 		}
 	}
 }
 
-#[derive(Debug)]
-pub struct Triple<T: Display + Copy + Into<JsonValue>> (pub [T; 3]);
-impl <T: Display + Copy + Into<JsonValue>> Display for Triple<T> {
+/// File format for exporting a whole session at once
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+	Json,
+	Csv,
+}
+
+impl ExportFormat {
+	pub fn extension(&self) -> &'static str {
+		match self {
+			Self::Json => "jsonl",
+			Self::Csv => "csv",
+		}
+	}
+}
+
+impl FromStr for ExportFormat {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match &*s.to_ascii_lowercase() {
+			"json" => Ok(Self::Json),
+			"csv" => Ok(Self::Csv),
+			_ => Err(format!("Unknown export format (json or csv): {s}"))
+		}
+	}
+}
+
+/// Where a sink's output goes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Destination {
+	Stdout,
+	/// Appended to
+	File(String),
+}
+
+/// An output format and where it goes, e.g. `json:scans.jsonl` (or just `json` for stdout)
+#[derive(Clone, Debug)]
+pub struct SinkSpec {
+	pub format: OutputFormat,
+	pub dest: Destination,
+}
+
+impl FromStr for SinkSpec {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (format, dest) = s.split_once(':').unwrap_or((s, "stdout"));
+		let dest = match dest {
+			"stdout" | "-" => Destination::Stdout,
+			"" => return Err(format!("Missing output destination: {s}")),
+			path => Destination::File(path.to_string()),
+		};
+		Ok(Self { format: format.parse()?, dest })
+	}
+}
+
+/// Shape of JSON output lines
+#[derive(Clone, Copy, Debug)]
+pub enum JsonStyle {
+	/// `{"scan":{...}}`, `{"power_level":41}`
+	Wrapped,
+	/// `{"type":"scan","idx":1,...}`, `{"type":"power_level","value":41}`
+	Typed,
+}
+
+impl FromStr for JsonStyle {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match &*s.to_ascii_lowercase() {
+			"wrapped" => Ok(Self::Wrapped),
+			"typed" => Ok(Self::Typed),
+			_ => Err(format!("Unknown JSON style: {s}"))
+		}
+	}
+}
+
+/// Layout of text output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextStyle {
+	/// Multi-line, tab-indented blocks
+	Full,
+	/// One line per scan or update
+	Compact,
+	/// Like full, but indented with spaces and never any escape codes
+	Plain,
+}
+
+impl FromStr for TextStyle {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match &*s.to_ascii_lowercase() {
+			"full" => Ok(Self::Full),
+			"compact" => Ok(Self::Compact),
+			"plain" => Ok(Self::Plain),
+			_ => Err(format!("Unknown text style: {s}"))
+		}
+	}
+}
+
+/// Parts of a scan result that can be selected for output with --fields
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanField {
+	Lab,
+	Luv,
+	Lch,
+	Yxy,
+	Rgb,
+	/// RGB scaled to 0-1
+	RgbF,
+	Hex,
+	/// Whether the color fits in sRGB, Display P3 and Adobe RGB
+	Gamut,
+	/// Scans per second over the last few seconds
+	Rate,
+}
+
+impl FromStr for ScanField {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match &*s.to_ascii_lowercase() {
+			"lab" => Ok(Self::Lab),
+			"luv" => Ok(Self::Luv),
+			"lch" => Ok(Self::Lch),
+			"yxy" => Ok(Self::Yxy),
+			"rgb" => Ok(Self::Rgb),
+			"rgb_f" => Ok(Self::RgbF),
+			"hex" => Ok(Self::Hex),
+			"gamut" => Ok(Self::Gamut),
+			"rate" => Ok(Self::Rate),
+			_ => Err(format!("Unknown field: {s}"))
+		}
+	}
+}
+
+/// Three components of a color, e.g. L*, a* and b*
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Triple<T> (pub [T; 3]);
+impl <T: Display> Display for Triple<T> {
 	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-		write!(f, "{}", self.0.map(|n| n.to_string()).join(", "))
+		write!(f, "{}", self.0.each_ref().map(|n| n.to_string()).join(", "))
+	}
+}
+impl <T> Triple<T> {
+	/// Each component through `f`
+	pub fn map<U>(self, f: impl FnMut(T) -> U) -> Triple<U> {
+		Triple(self.0.map(f))
+	}
+}
+impl Triple<u8> {
+	/// CSS-style hex string, e.g. `#7F8CA1`
+	pub fn to_hex(&self) -> String {
+		let [r, g, b] = self.0;
+		format!("#{r:02X}{g:02X}{b:02X}")
+	}
+	/// Each component scaled to 0-1
+	pub fn to_unit(&self) -> Triple<f32> {
+		self.map(|n| n as f32 / 255.0)
+	}
+}
+impl Triple<f32> {
+	/// Euclidean length, e.g. the CIE76 ΔE of a difference of two Lab triples
+	pub fn norm(&self) -> f32 {
+		self.0.iter().map(|n| n * n).sum::<f32>().sqrt()
+	}
+}
+impl Add for Triple<f32> {
+	type Output = Self;
+	fn add(self, other: Self) -> Self {
+		Triple([0, 1, 2].map(|i| self.0[i] + other.0[i]))
+	}
+}
+impl Sub for Triple<f32> {
+	type Output = Self;
+	fn sub(self, other: Self) -> Self {
+		Triple([0, 1, 2].map(|i| self.0[i] - other.0[i]))
+	}
+}
+impl Mul<f32> for Triple<f32> {
+	type Output = Self;
+	fn mul(self, k: f32) -> Self {
+		self.map(|n| n * k)
+	}
+}
+/// The same numbers, not scaled (see [Triple::to_unit] for that)
+impl From<Triple<u8>> for Triple<f32> {
+	fn from(t: Triple<u8>) -> Self {
+		t.map(f32::from)
+	}
+}
+/// Rounded, and clamped to 0-255
+impl From<Triple<f32>> for Triple<u8> {
+	fn from(t: Triple<f32>) -> Self {
+		t.map(|n| n.round().clamp(0.0, 255.0) as u8)
+	}
+}
+/// A JSON array
+impl <T: Into<JsonValue>> From<Triple<T>> for JsonValue {
+	fn from(t: Triple<T>) -> Self {
+		JsonValue::Array(t.0.map(Into::into).into())
 	}
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ScanResult {
+	/// Unique across sessions, unlike `idx`
+	pub id: Uuid,
+	/// Counts from 1 in each session (or download of stored scans), for display
 	pub idx: usize,
 	pub lab: Triple<f32>,
 	pub luv: Triple<f32>,
 	pub lch: Triple<f32>,
 	pub yxy: Triple<f32>,
 	pub rgb: Triple<u8>,
+	/// Scans per second, `None` until there are two scans to go by
+	pub rate: Option<f32>,
+	/// Stored on the device and downloaded later rather than scanned live
+	pub historical: bool,
+	/// Given along with the scan command, e.g. a sample name
+	pub label: Option<String>,
+	/// Where the scan was taken, if known
+	pub location: Option<Location>,
+	/// The message it was parsed from, only with [DeviceConfig::keep_raw](crate::device::DeviceConfig::keep_raw)
+	pub raw: Option<Vec<u8>>,
+}
+
+/// In degrees, parsed from `lat,lon`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+	pub lat: f64,
+	pub lon: f64,
+}
+
+impl FromStr for Location {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let parse = |n: Option<&str>| n.and_then(|n| n.trim().parse::<f64>().ok());
+		let mut parts = s.split(',');
+		match (parse(parts.next()), parse(parts.next()), parts.next()) {
+			(Some(lat), Some(lon), None) if lat.abs() <= 90.0 && lon.abs() <= 180.0 => Ok(Self { lat, lon }),
+			_ => Err(format!("Invalid location (expected lat,lon): {s}")),
+		}
+	}
+}
+
+impl ScanResult {
+	/// CSS-style hex string of the RGB value, e.g. `#FF8040`
+	pub fn to_hex(&self) -> String {
+		self.rgb.to_hex()
+	}
+}
+
+/// One line, e.g. `#5 Lab(50.2, -5.1, 12.3) RGB(128, 64, 32)`
+impl Display for ScanResult {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		write!(f, "#{} Lab({}) RGB({})", self.idx, self.lab, self.rgb)
+	}
+}
+
+#[derive(Debug)]
+pub struct DeviceListing {
+	pub address: String,
+	pub name: Option<String>,
+	pub rssi: Option<i16>,
+	pub capable: bool,
 }
+
+/// Formats output as text; the I/O is done by [Output]. `None` means nothing to print.
 pub trait OutputPrinter: Send {
-	fn print_result(&self, res: ScanResult);
-	fn print_misc(&self, key: &str, value: JsonValue);
+	fn format_result(&self, res: &ScanResult) -> Option<String>;
+	fn format_misc(&self, key: &str, value: JsonValue) -> Option<String>;
+	fn format_devices(&self, devices: &[DeviceListing]) -> Option<String>;
+	fn format_warning(&self, msg: &str) -> Option<String>;
+	fn format_error(&self, msg: &str) -> Option<String>;
+	fn format_ready(&self) -> Option<String>;
+}
+
+/// JSON names of every kind of output, for [EventFilter]
+pub const EVENT_NAMES: &[&str] = &[
+	"scan", "calibrated", "power_level", "session", "link_stats", "device_info", "unknown",
+	"warning", "error", "ready", "devices", "adapters", "heartbeat", "gradient",
+];
+
+/// Which kinds of output to print, by their JSON names (scan, power_level, warning, ...)
+#[derive(Clone, Debug, Default)]
+pub struct EventFilter(Option<Vec<String>>);
+impl EventFilter {
+	/// Everything
+	pub fn all() -> Self {
+		Self(None)
+	}
+	pub fn only(names: Vec<String>) -> Self {
+		Self(Some(names))
+	}
+	pub fn allows(&self, name: &str) -> bool {
+		self.0.as_ref().is_none_or(|names| names.iter().any(|n| n == name))
+	}
 }
 
-pub struct TextPrinter;
+/// A printer together with the writer its output goes to
+pub struct Output {
+	pub printer: Box<dyn OutputPrinter>,
+	pub writer: LineWriter,
+	pub filter: EventFilter,
+}
+impl Output {
+	pub fn print_result(&self, res: &ScanResult) {
+		if !self.filter.allows("scan") { return; }
+		if let Some(text) = self.printer.format_result(res) { self.writer.write(text); }
+	}
+	pub fn print_misc(&self, key: &str, value: JsonValue) {
+		if !self.filter.allows(key) { return; }
+		if let Some(text) = self.printer.format_misc(key, value) { self.writer.write(text); }
+	}
+	pub fn print_devices(&self, devices: &[DeviceListing]) {
+		if !self.filter.allows("devices") { return; }
+		if let Some(text) = self.printer.format_devices(devices) { self.writer.write(text); }
+	}
+	pub fn print_warning(&self, msg: &str) {
+		if !self.filter.allows("warning") { return; }
+		if let Some(text) = self.printer.format_warning(msg) { self.writer.write(text); }
+	}
+	pub fn print_error(&self, msg: &str) {
+		if !self.filter.allows("error") { return; }
+		if let Some(text) = self.printer.format_error(msg) { self.writer.write(text); }
+	}
+	pub fn print_ready(&self) {
+		if !self.filter.allows("ready") { return; }
+		if let Some(text) = self.printer.format_ready() { self.writer.write(text); }
+	}
+	pub fn print_event(&self, event: &Event) {
+		match event {
+			Event::Scan(res) => self.print_result(res),
+			Event::Calibrated => self.print_misc("calibrated", true.into()),
+			Event::PowerLevel(level) => self.print_misc("power_level", (*level).into()),
+			Event::Session { scan_count, duration_secs } => self.print_misc("session", jzon::object! {
+				scan_count: *scan_count,
+				duration_secs: *duration_secs,
+			}),
+			Event::LinkStats(stats) => self.print_misc("link_stats", jzon::object! {
+				notifications: stats.notifications,
+				unknown: stats.unknown,
+				checksum_failures: stats.checksum_failures,
+				bad_scans: stats.bad_scans,
+				unsolicited: stats.unsolicited,
+				write_retries: stats.write_retries,
+				timeouts: stats.timeouts,
+				answered: stats.answered,
+				latency_min_ms: stats.latency_min.map(latency_ms),
+				latency_avg_ms: stats.latency_avg().map(latency_ms),
+				latency_max_ms: stats.latency_max.map(latency_ms),
+			}),
+			Event::DeviceInfo(info) => self.print_misc("device_info", info.clone().into()),
+			Event::Unknown(msg) => self.print_misc("unknown", hex::encode_upper(msg).into()),
+			Event::Warning(msg) => self.print_warning(msg),
+			Event::Error(msg) => self.print_error(msg),
+			Event::Ready => self.print_ready(),
+			Event::Heartbeat => {},
+		}
+	}
+}
+
+/// Every [Output] in use, each getting everything
+pub struct Sinks(pub Vec<Output>);
+impl Sinks {
+	pub fn print_misc(&self, key: &str, value: JsonValue) {
+		for sink in &self.0 { sink.print_misc(key, value.clone()); }
+	}
+	pub fn print_devices(&self, devices: &[DeviceListing]) {
+		for sink in &self.0 { sink.print_devices(devices); }
+	}
+	pub fn print_event(&self, event: &Event) {
+		for sink in &self.0 { sink.print_event(event); }
+	}
+}
+
+pub struct TextPrinter {
+	pub fields: Vec<ScanField>,
+	pub style: TextStyle,
+	/// Highlight warnings and errors with ANSI colors
+	pub colors: bool,
+	/// Tell the user what to do next, e.g. once connected
+	pub hints: bool,
+}
+impl TextPrinter {
+	/// Puts each item on its own indented line, or all of them on the header line in compact style
+	fn format_block(&self, header: String, items: Vec<String>) -> String {
+		let sep = match self.style {
+			TextStyle::Full => "\n\t",
+			TextStyle::Compact => "; ",
+			TextStyle::Plain => "\n    ",
+		};
+		[header].into_iter().chain(items).collect::<Vec<_>>().join(sep)
+	}
+}
 impl OutputPrinter for TextPrinter {
-	fn print_result(&self, res: ScanResult) {
-		println!("Scan result #: {}", res.idx);
-		println!("\tLab: {}", res.lab);
-		println!("\tLuv: {}", res.luv);
-		println!("\tLch: {}", res.lch);
-		println!("\tyxY: {}", res.yxy);
-		println!("\tRGB: {}", res.rgb);
+	fn format_result(&self, res: &ScanResult) -> Option<String> {
+		let items = self.fields.iter().map(|field| match field {
+			ScanField::Lab => format!("Lab: {}", res.lab),
+			ScanField::Luv => format!("Luv: {}", res.luv),
+			ScanField::Lch => format!("Lch: {}", res.lch),
+			ScanField::Yxy => format!("yxY: {}", res.yxy),
+			ScanField::Rgb => format!("RGB: {} ({})", res.rgb, res.to_hex()),
+			ScanField::RgbF => format!("RGB (0-1): {}", res.rgb.to_unit().map(|n| format!("{n:.3}"))),
+			ScanField::Hex => format!("Hex: {}", res.to_hex()),
+			ScanField::Gamut => {
+				let xyz = yxy_to_xyz(&res.yxy);
+				let mark = |fits: bool| if fits { "✓" } else { "✗" };
+				format!(
+					"{}: [sRGB{} P3{} AdobeRGB{}]", t("gamut"),
+					mark(in_srgb(&xyz)), mark(in_display_p3(&xyz)), mark(in_adobe_rgb(&xyz)),
+				)
+			},
+			ScanField::Rate => match res.rate {
+				Some(rate) => format!("{}: {rate:.2}/s", t("rate")),
+				None => format!("{}: -", t("rate")),
+			},
+		}).collect();
+		let title = if res.historical { t("stored_scan") } else { t("scan_result") };
+		let label = res.label.as_ref().map_or(String::new(), |l| format!(" ({l})"));
+		Some(self.format_block(format!("{}: {}{}", title, res.idx, label), items))
+	}
+	fn format_misc(&self, key: &str, value: JsonValue) -> Option<String> {
+		Some(format!("{}: {} = {}", t("update"), key, value))
+	}
+	fn format_devices(&self, devices: &[DeviceListing]) -> Option<String> {
+		let items = devices.iter().map(|dev| {
+			let name = dev.name.as_deref().unwrap_or(t("unnamed"));
+			let rssi = dev.rssi.map_or("?".to_string(), |r| r.to_string());
+			let capable = if dev.capable { format!(", {}", t("capable")) } else { String::new() };
+			format!("{} {} (RSSI {}{})", dev.address, name, rssi, capable)
+		}).collect();
+		Some(self.format_block(format!("{}: {}", t("devices_found"), devices.len()), items))
+	}
+	fn format_warning(&self, msg: &str) -> Option<String> {
+		if self.colors {
+			Some(format!("\x1b[33m{}: {msg}\x1b[0m", t("warning")))
+		} else {
+			Some(format!("{}: {msg}", t("warning")))
+		}
 	}
-	fn print_misc(&self, key: &str, value: JsonValue) {
-		println!("Update: {} = {}", key, value);
+	fn format_error(&self, msg: &str) -> Option<String> {
+		if self.colors {
+			Some(format!("\x1b[31m{}: {msg}\x1b[0m", t("error")))
+		} else {
+			Some(format!("{}: {msg}", t("error")))
+		}
+	}
+	fn format_ready(&self) -> Option<String> {
+		if !self.hints { return None; }
+		Some(t("ready_hint").to_string())
 	}
 }
 
-pub struct JSONPrinter;
+/// A number rounded to two decimals
+fn json_float(n: f32) -> JsonValue {
+	json_rounded(n, 2)
+}
+
+/// Milliseconds, to a tenth
+fn latency_ms(latency: Duration) -> JsonValue {
+	json_rounded(latency.as_secs_f32() * 1000.0, 1)
+}
+
+/// A number rounded to this many decimals
+fn json_rounded(n: f32, decimals: u8) -> JsonValue {
+	let scale = 10f32.powi(decimals as i32);
+	JsonValue::Number(
+		// These dances are the easiest way I found to strip the float noise
+		jzon::number::Number::from_parts(n.is_sign_positive(), (n.abs() * scale).round() as u64, -(decimals as i16))
+	)
+}
+
+pub struct JSONPrinter {
+	pub fields: Vec<ScanField>,
+	pub style: JsonStyle,
+	/// Color triples as `{"l":..,"a":..,"b":..}` instead of arrays
+	pub named_components: bool,
+}
+impl JSONPrinter {
+	/// Typed style keeps the object's own key order after "type"; non-objects go under "value"
+	fn format_line(&self, kind: &str, value: JsonValue) -> String {
+		let mut obj = JsonValue::new_object();
+		match self.style {
+			JsonStyle::Wrapped => obj.insert(kind, value).unwrap(),
+			JsonStyle::Typed => {
+				obj.insert("type", kind).unwrap();
+				if let JsonValue::Object(fields) = value {
+					for (key, value) in fields.iter() {
+						obj.insert(key, value.clone()).unwrap();
+					}
+				} else {
+					obj.insert("value", value).unwrap();
+				}
+			}
+		}
+		obj.to_string()
+	}
+}
 impl OutputPrinter for JSONPrinter {
-	fn print_result(&self, res: ScanResult) {
-		let json_triple = |t: Triple<f32>| JsonValue::Array(t.0.map(|n| JsonValue::Number(
-			// These dances are the easiest way I found to strip the float noise
-			jzon::number::Number::from_parts(n.is_sign_positive(), (n.abs() * 100.0).round() as u64, -2)
-		)).into()); 
-		let scan = jzon::object! {
-			lab: json_triple(res.lab),
-			luv: json_triple(res.luv),
-			lch: json_triple(res.lch),
-			yxy: json_triple(res.yxy),
-			rgb: Vec::from(res.rgb.0),
+	fn format_result(&self, res: &ScanResult) -> Option<String> {
+		let json_floats = |t: &Triple<f32>| t.map(json_float);
+		let json_triple = |names: [&str; 3], values: Triple<JsonValue>| if self.named_components {
+			let mut obj = JsonValue::new_object();
+			for (name, value) in names.into_iter().zip(values.0) {
+				obj.insert(name, value).unwrap();
+			}
+			obj
+		} else {
+			values.into()
 		};
-		let obj = jzon::object! { scan: scan };
-		println!("{obj}");
+		let mut scan = JsonValue::new_object();
+		scan.insert("id", res.id.to_string()).unwrap();
+		if let JsonStyle::Typed = self.style {
+			scan.insert("idx", res.idx).unwrap();
+		}
+		if res.historical {
+			scan.insert("historical", true).unwrap();
+		}
+		if let Some(label) = &res.label {
+			scan.insert("label", label.as_str()).unwrap();
+		}
+		if let Some(loc) = &res.location {
+			scan.insert("location", jzon::object! { lat: loc.lat, lon: loc.lon }).unwrap();
+		}
+		if let Some(raw) = &res.raw {
+			scan.insert("raw", hex::encode_upper(raw)).unwrap();
+		}
+		for field in &self.fields {
+			let (key, value) = match field {
+				ScanField::Lab => ("lab", json_triple(["l", "a", "b"], json_floats(&res.lab))),
+				ScanField::Luv => ("luv", json_triple(["l", "u", "v"], json_floats(&res.luv))),
+				ScanField::Lch => ("lch", json_triple(["l", "c", "h"], json_floats(&res.lch))),
+				ScanField::Yxy => ("yxy", json_triple(["Y", "x", "y"], json_floats(&res.yxy))),
+				ScanField::Rgb => ("rgb", json_triple(["r", "g", "b"], res.rgb.map(JsonValue::from))),
+				// Two decimals would be coarser than the 8-bit values
+				ScanField::RgbF => ("rgb_f", json_triple(["r", "g", "b"], res.rgb.to_unit().map(|n| json_rounded(n, 3)))),
+				ScanField::Hex => ("hex", res.to_hex().into()),
+				ScanField::Gamut => {
+					let xyz = yxy_to_xyz(&res.yxy);
+					("gamut", jzon::object! {
+						srgb: in_srgb(&xyz),
+						display_p3: in_display_p3(&xyz),
+						adobe_rgb: in_adobe_rgb(&xyz),
+					})
+				},
+				ScanField::Rate => ("rate", res.rate.map(json_float).into()),
+			};
+			scan.insert(key, value).unwrap();
+			// Along with RGB, as in text output, unless it's coming anyway
+			if *field == ScanField::Rgb && !self.fields.contains(&ScanField::Hex) {
+				scan.insert("hex", res.to_hex()).unwrap();
+			}
+		}
+		Some(self.format_line("scan", scan))
 	}
-	fn print_misc(&self, key: &str, value: JsonValue) {
-		let mut obj = JsonValue::new_object();
-		obj.insert(key, value).unwrap();
-		println!("{}", obj);
+	fn format_misc(&self, key: &str, value: JsonValue) -> Option<String> {
+		Some(self.format_line(key, value))
+	}
+	fn format_devices(&self, devices: &[DeviceListing]) -> Option<String> {
+		let list: Vec<JsonValue> = devices.iter().map(|dev| jzon::object! {
+			address: dev.address.clone(),
+			name: dev.name.clone(),
+			rssi: dev.rssi,
+			capable: dev.capable,
+		}).collect();
+		Some(self.format_line("devices", list.into()))
+	}
+	fn format_warning(&self, msg: &str) -> Option<String> {
+		Some(self.format_line("warning", msg.into()))
+	}
+	fn format_error(&self, msg: &str) -> Option<String> {
+		Some(self.format_line("error", msg.into()))
+	}
+	fn format_ready(&self) -> Option<String> {
+		Some(self.format_line("ready", true.into()))
 	}
 	
 }
 
+/// A CSV row per scan, with a header before the first (unless made [CsvPrinter::without_header]). Nothing else is printed.
+pub struct CsvPrinter {
+	pub fields: Vec<ScanField>,
+	header_written: AtomicBool,
+}
+impl CsvPrinter {
+	pub fn new(fields: Vec<ScanField>) -> Self {
+		Self { fields, header_written: AtomicBool::new(false) }
+	}
+	/// Rows only, e.g. for appending to a file that already has the header
+	pub fn without_header(fields: Vec<ScanField>) -> Self {
+		Self { fields, header_written: AtomicBool::new(true) }
+	}
+	pub fn header(&self) -> String {
+		let mut columns = vec!["id", "idx"];
+		for field in &self.fields {
+			columns.extend_from_slice(match field {
+				ScanField::Lab => &["lab_l", "lab_a", "lab_b"],
+				ScanField::Luv => &["luv_l", "luv_u", "luv_v"],
+				ScanField::Lch => &["lch_l", "lch_c", "lch_h"],
+				ScanField::Yxy => &["yxy_Y", "yxy_x", "yxy_y"],
+				ScanField::Rgb => &["rgb_r", "rgb_g", "rgb_b"],
+				ScanField::RgbF => &["rgb_f_r", "rgb_f_g", "rgb_f_b"],
+				ScanField::Hex => &["hex"],
+				ScanField::Gamut => &["srgb", "display_p3", "adobe_rgb"],
+				ScanField::Rate => &["rate"],
+			});
+		}
+		columns.join(",")
+	}
+}
+impl OutputPrinter for CsvPrinter {
+	fn format_result(&self, res: &ScanResult) -> Option<String> {
+		let floats = |t: &Triple<f32>| t.map(|n| format!("{n:.2}")).0.to_vec();
+		let mut row = vec![res.id.to_string(), res.idx.to_string()];
+		for field in &self.fields {
+			row.extend(match field {
+				ScanField::Lab => floats(&res.lab),
+				ScanField::Luv => floats(&res.luv),
+				ScanField::Lch => floats(&res.lch),
+				ScanField::Yxy => floats(&res.yxy),
+				ScanField::Rgb => res.rgb.map(|n| n.to_string()).0.to_vec(),
+				ScanField::RgbF => res.rgb.to_unit().map(|n| format!("{n:.3}")).0.to_vec(),
+				ScanField::Hex => vec![res.to_hex()],
+				ScanField::Gamut => {
+					let xyz = yxy_to_xyz(&res.yxy);
+					[in_srgb(&xyz), in_display_p3(&xyz), in_adobe_rgb(&xyz)].map(|fits| fits.to_string()).to_vec()
+				},
+				ScanField::Rate => vec![res.rate.map_or(String::new(), |rate| format!("{rate:.2}"))],
+			});
+		}
+		let row = row.join(",");
+		if self.header_written.swap(true, Relaxed) {
+			Some(row)
+		} else {
+			Some(format!("{}\n{}", self.header(), row))
+		}
+	}
+	fn format_misc(&self, _key: &str, _value: JsonValue) -> Option<String> {
+		None
+	}
+	fn format_devices(&self, _devices: &[DeviceListing]) -> Option<String> {
+		None
+	}
+	fn format_warning(&self, _msg: &str) -> Option<String> {
+		None
+	}
+	fn format_error(&self, _msg: &str) -> Option<String> {
+		None
+	}
+	fn format_ready(&self) -> Option<String> {
+		None
+	}
+}
+
+/// Percent-encodes whatever would break a `key=value` field of [LinePrinter] output apart
+fn escape_line_value(value: &str) -> String {
+	value.chars().map(|c| match c {
+		'%' | '=' | '#' => format!("%{:02X}", c as u32),
+		c if c.is_whitespace() || c.is_control() => c.to_string().bytes().map(|b| format!("%{b:02X}")).collect(),
+		c => c.to_string(),
+	}).collect()
+}
+
+/// One line per scan, for awk and friends. Everything else becomes a `#` comment line.
+pub struct LinePrinter {
+	pub scans_only: bool,
+}
+impl OutputPrinter for LinePrinter {
+	fn format_result(&self, res: &ScanResult) -> Option<String> {
+		let [l, a, b] = res.lab.0;
+		let stored = if res.historical { " stored=1" } else { "" };
+		let label = res.label.as_deref().map_or(String::new(), |label| format!(" label={}", escape_line_value(label)));
+		Some(format!("{} L={:.2} a={:.2} b={:.2} rgb={}{}{}", res.idx, l, a, b, res.to_hex(), stored, label))
+	}
+	fn format_misc(&self, key: &str, value: JsonValue) -> Option<String> {
+		if self.scans_only { return None; }
+		Some(format!("# {} {}", key, value))
+	}
+	fn format_devices(&self, devices: &[DeviceListing]) -> Option<String> {
+		if self.scans_only || devices.is_empty() { return None; }
+		Some(devices.iter().map(|dev| {
+			let name = dev.name.as_deref().unwrap_or("-");
+			let rssi = dev.rssi.map_or("-".to_string(), |r| r.to_string());
+			format!("# device {} {} {} {}", dev.address, name, rssi, dev.capable)
+		}).collect::<Vec<_>>().join("\n"))
+	}
+	fn format_warning(&self, msg: &str) -> Option<String> {
+		if self.scans_only { return None; }
+		Some(format!("# warning {msg}"))
+	}
+	fn format_error(&self, msg: &str) -> Option<String> {
+		if self.scans_only { return None; }
+		Some(format!("# error {msg}"))
+	}
+	fn format_ready(&self) -> Option<String> {
+		if self.scans_only { return None; }
+		Some("# ready".to_string())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::testing::scan_result;
+	use super::*;
+	
+	#[test]
+	fn sink_specs() {
+		let spec: SinkSpec = "json:/var/log/bluecolor.jsonl".parse().unwrap();
+		assert!(matches!(spec.format, OutputFormat::Json));
+		assert_eq!(spec.dest, Destination::File("/var/log/bluecolor.jsonl".to_string()));
+		assert_eq!("text".parse::<SinkSpec>().unwrap().dest, Destination::Stdout);
+		assert_eq!("csv:stdout".parse::<SinkSpec>().unwrap().dest, Destination::Stdout);
+		assert!("json:".parse::<SinkSpec>().is_err());
+		assert!("yaml:out.yaml".parse::<SinkSpec>().is_err());
+		assert_eq!("CSV".parse(), Ok(ExportFormat::Csv));
+		assert!("cgats".parse::<ExportFormat>().is_err());
+	}
+	
+	#[test]
+	fn csv_rows() {
+		let printer = CsvPrinter::new(vec![ScanField::Lab, ScanField::Hex, ScanField::Rate]);
+		let res = ScanResult {
+			id: Uuid::nil(),
+			idx: 1,
+			lab: Triple([92.58, -0.27, 0.54]),
+			luv: Triple([0.0; 3]),
+			lch: Triple([0.0; 3]),
+			yxy: Triple([0.0; 3]),
+			rgb: Triple([234, 234, 231]),
+			rate: None,
+			historical: false,
+			label: None,
+			location: None,
+			raw: None,
+		};
+		assert_eq!(printer.format_result(&res).unwrap(), "id,idx,lab_l,lab_a,lab_b,hex,rate\n00000000-0000-0000-0000-000000000000,1,92.58,-0.27,0.54,#EAEAE7,");
+		assert_eq!(printer.format_result(&ScanResult { idx: 2, rate: Some(0.5), ..res.clone() }).unwrap(), "00000000-0000-0000-0000-000000000000,2,92.58,-0.27,0.54,#EAEAE7,0.50");
+		assert_eq!(res.to_string(), "#1 Lab(92.58, -0.27, 0.54) RGB(234, 234, 231)");
+		
+		let printer = CsvPrinter::without_header(vec![ScanField::Hex]);
+		assert_eq!(printer.format_result(&res).unwrap(), "00000000-0000-0000-0000-000000000000,1,#EAEAE7");
+	}
+	
+	#[test]
+	fn json_fields() {
+		let printer = JSONPrinter { fields: vec![ScanField::Lab, ScanField::Rgb], style: JsonStyle::Wrapped, named_components: false };
+		assert_eq!(printer.format_result(&scan_result(1)).unwrap(), r##"{"scan":{"id":"00000000-0000-0000-0000-000000000000","lab":[92.58,-0.27,0.54],"rgb":[234,234,231],"hex":"#EAEAE7"}}"##);
+		let printer = JSONPrinter { fields: vec![ScanField::Hex, ScanField::Rgb], style: JsonStyle::Typed, named_components: false };
+		assert_eq!(printer.format_result(&scan_result(1)).unwrap(), r##"{"type":"scan","id":"00000000-0000-0000-0000-000000000000","idx":1,"hex":"#EAEAE7","rgb":[234,234,231]}"##);
+	}
+	
+	#[test]
+	fn line_format() {
+		let printer = LinePrinter { scans_only: true };
+		assert_eq!(printer.format_result(&scan_result(3)).unwrap(), "3 L=92.58 a=-0.27 b=0.54 rgb=#EAEAE7");
+		let labeled = ScanResult { label: Some("swatch 3=50%".to_string()), ..scan_result(3) };
+		assert_eq!(printer.format_result(&labeled).unwrap(), "3 L=92.58 a=-0.27 b=0.54 rgb=#EAEAE7 label=swatch%203%3D50%25");
+		let stored = ScanResult { historical: true, ..labeled };
+		assert_eq!(printer.format_result(&stored).unwrap(), "3 L=92.58 a=-0.27 b=0.54 rgb=#EAEAE7 stored=1 label=swatch%203%3D50%25");
+		assert_eq!(printer.format_misc("power_level", 41.into()), None);
+	}
+	
+	#[test]
+	fn event_filter() {
+		let filter = EventFilter::only(vec!["scan".to_string(), "error".to_string()]);
+		assert!(filter.allows("scan"));
+		assert!(filter.allows("error"));
+		assert!(!filter.allows("power_level"));
+		assert!(EventFilter::all().allows("power_level"));
+	}
+	
+	#[test]
+	fn locations() {
+		assert_eq!("52.52, 13.405".parse::<Location>().unwrap(), Location { lat: 52.52, lon: 13.405 });
+		assert_eq!("-33.9,151.2".parse::<Location>().unwrap(), Location { lat: -33.9, lon: 151.2 });
+		assert!("91,0".parse::<Location>().is_err());
+		assert!("1,2,3".parse::<Location>().is_err());
+		assert!("here".parse::<Location>().is_err());
+	}
+	
+	#[test]
+	fn unit_rgb() {
+		assert_eq!(Triple([0u8, 255, 128]).to_unit(), Triple([0.0, 1.0, 128.0 / 255.0]));
+		let rounded = |n: u8| json_rounded(Triple([n, n, n]).to_unit().0[0], 3).to_string();
+		assert_eq!(rounded(0), "0");
+		assert_eq!(rounded(1), "0.004");
+		assert_eq!(rounded(170), "0.667");
+		assert_eq!(rounded(254), "0.996");
+		assert_eq!(rounded(255), "1");
+	}
+	
+	#[test]
+	fn triple_math() {
+		// A spread of Lab-ish values, including negatives and zeros
+		let samples: Vec<Triple<f32>> = (0..50)
+			.map(|i| i as f32)
+			.map(|i| Triple([(i * 7.3) % 100.0, (i * 13.1) % 256.0 - 128.0, 64.0 - (i * 5.7) % 128.0]))
+			.collect();
+		for &a in &samples {
+			assert_eq!((a - a).norm(), 0.0);
+			assert!(((a * -2.0).norm() - 2.0 * a.norm()).abs() < 1e-3);
+			for &b in &samples {
+				let back = a - b + b;
+				assert!((back - a).norm() < 1e-3, "{a} - {b} + {b} = {back}");
+				assert_eq!(a + b, b + a);
+				assert!(((a - b).norm() - (b - a).norm()).abs() < 1e-6);
+			}
+		}
+		assert_eq!(Triple([3.0, 4.0, 0.0]).norm(), 5.0);
+		
+		for n in 0..=255u8 {
+			let t = Triple([n, 255 - n, n / 2]);
+			assert_eq!(Triple::<u8>::from(Triple::<f32>::from(t)), t);
+		}
+		assert_eq!(Triple::<u8>::from(Triple([-3.0, 127.5, 300.0])), Triple([0, 128, 255]));
+		
+		assert_eq!(JsonValue::from(Triple([1u8, 2, 3])).to_string(), "[1,2,3]");
+		assert_eq!(Triple([1.5, -2.25, 0.0]).map(json_float).to_string(), "1.5, -2.25, 0");
+	}
+	
+}
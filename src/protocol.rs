@@ -0,0 +1,361 @@
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+use byteorder::{LittleEndian, ReadBytesExt};
+use log::{debug, info, warn};
+use uuid::Uuid;
+use crate::output::{ScanResult, Triple};
+
+lazy_static! {
+	
+	/// Command destination service
+	pub static ref WRITE_SVC_ID: Uuid = Uuid::parse_str("0000ffe5-0000-1000-8000-00805f9b34fb").unwrap();
+	/// Command destination characteristic
+	pub static ref WRITE_CHR_ID: Uuid = Uuid::parse_str("0000ffe9-0000-1000-8000-00805f9b34fb").unwrap();
+	
+	/// Notification service
+	pub static ref NOTIF_SVC_ID: Uuid = Uuid::parse_str("0000ffe0-0000-1000-8000-00805f9b34fb").unwrap();
+	/// Notification characteristic
+	pub static ref NOTIF_CHR_ID: Uuid = Uuid::parse_str("0000ffe4-0000-1000-8000-00805f9b34fb").unwrap();
+	
+	/// The command to trigger a color scan (results sent as AB44... notification)
+	pub static ref SCAN_CMD: Vec<u8> = frame(&[0xAB, 0x44, 0x00, 0x00, 0x00, 0x00, 0x36, 0x00]);
+	
+	/// The command to trigger a calibration (result: AB202E00020000002DF4)
+	pub static ref CALIBRATE_CMD: Vec<u8> = frame(&[0xAB, 0x20, 0x2E, 0x00, 0x02, 0x00]);
+	
+	/// The command to request battery level
+	pub static ref BATTERY_CMD: Vec<u8> = frame(&[0xAB, 0x20, 0x0B, 0x00, 0x02, 0x00]);
+	
+	/// The command to request device info
+	pub static ref INFO_CMD: Vec<u8> = frame(&[0xAB, 0x40, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00]);
+	
+}
+
+/// CRC-16/MODBUS, which every AB frame (both directions) ends with, little-endian
+pub fn checksum(data: &[u8]) -> u16 {
+	let mut crc: u16 = 0xFFFF;
+	for &byte in data {
+		crc ^= byte as u16;
+		for _ in 0..8 {
+			crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+		}
+	}
+	crc
+}
+
+/// Append the checksum to a frame body
+pub fn frame(body: &[u8]) -> Vec<u8> {
+	let mut frame = body.to_vec();
+	frame.extend_from_slice(&checksum(body).to_le_bytes());
+	frame
+}
+
+/// Notifications we know how to parse
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+	Scan,
+	Calibrated,
+	PowerLevel,
+	DeviceInfo,
+}
+
+impl MessageKind {
+	/// How many bytes the parser reads. Anything shorter is a fragment.
+	pub const fn min_len(self) -> usize {
+		match self {
+			// 8 header, 4 triples of i16, 4 CMYK, 3 RGB
+			MessageKind::Scan => 39,
+			MessageKind::Calibrated => 3,
+			MessageKind::PowerLevel => 8,
+			MessageKind::DeviceInfo => 26,
+		}
+	}
+}
+
+/// Type bytes following the AB marker for each known notification
+const MESSAGE_TYPES: &[(&[u8], MessageKind)] = &[
+	(&[0x44], MessageKind::Scan),
+	(&[0x20, 0x2E], MessageKind::Calibrated),
+	(&[0x20, 0x0B], MessageKind::PowerLevel),
+	(&[0x40, 0x00], MessageKind::DeviceInfo),
+];
+
+/// Type of an AB message, `None` if it isn't one or the type is unknown
+pub fn message_kind(msg: &[u8]) -> Option<MessageKind> {
+	let (&0xAB, rest) = msg.split_first()? else { return None };
+	MESSAGE_TYPES.iter().find(|(prefix, _)| rest.starts_with(prefix)).map(|(_, kind)| *kind)
+}
+
+/// Where the fields of a scan message are
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScanLayout {
+	/// Offsets of the Lab, Luv, LCh and Yxy triples (i16, hundredths) and the RGB bytes
+	pub lab: usize,
+	pub luv: usize,
+	pub lch: usize,
+	pub yxy: usize,
+	pub rgb: usize,
+}
+
+/// The LS170 layout: 8 header, 4 triples of i16, 4 CMYK (useless in practice), 3 RGB, and whatever follows
+pub const SCAN_LAYOUT: ScanLayout = ScanLayout { lab: 8, luv: 14, lch: 20, yxy: 26, rgb: 36 };
+
+/// Parse a complete AB44 message. The id and rate are left for the caller.
+///
+/// Messages of other firmware may well have the fields elsewhere, so values that can't be right
+/// (e.g. L* over 100) are an error rather than confidently wrong numbers.
+pub fn parse_scan_result(msg: &[u8], idx: usize) -> Result<ScanResult, anyhow::Error> {
+	
+	if msg.len() < MessageKind::Scan.min_len() {
+		return Err(anyhow::Error::msg(format!(
+			"Scan message too short ({} bytes): {}", msg.len(), hex::encode_upper(msg),
+		)));
+	}
+	let layout = SCAN_LAYOUT;
+	
+	let read_floats = |offset: usize| {
+		let mut cur = Cursor::new(&msg[offset..]);
+		Triple((0..3).map(|_| {
+			(cur.read_i16::<LittleEndian>().unwrap() as f32) / 100.0
+		}).collect::<Vec<f32>>().try_into().unwrap())
+	};
+	let lab = read_floats(layout.lab);
+	let luv = read_floats(layout.luv);
+	let lch = read_floats(layout.lch);
+	let yxy = read_floats(layout.yxy);
+	
+	if !plausible(&lab, &luv, &lch, &yxy) {
+		return Err(anyhow::Error::msg(format!(
+			"Scan message with unknown layout ({} bytes): {}", msg.len(), hex::encode_upper(msg),
+		)));
+	}
+	
+	let rgb = Triple(msg[layout.rgb..(layout.rgb + 3)].try_into().unwrap());
+	
+	Ok(ScanResult { id: Uuid::nil(), idx, lab, luv, lch, yxy, rgb, rate: None, historical: false, label: None, location: None, raw: None })
+	
+}
+
+/// Whether the values are in range for a color at all: lightness 0-100, chroma not negative,
+/// hue in degrees, Y not negative and the chromaticity (in percent) inside the diagram's triangle
+fn plausible(lab: &Triple<f32>, luv: &Triple<f32>, lch: &Triple<f32>, yxy: &Triple<f32>) -> bool {
+	let lightness = |l: f32| (0.0..=100.0).contains(&l);
+	let [_, c, h] = lch.0;
+	let [y_lum, x, y] = yxy.0;
+	lightness(lab.0[0]) && lightness(luv.0[0]) && lightness(lch.0[0])
+		&& c >= 0.0 && (0.0..=360.0).contains(&h)
+		&& y_lum >= 0.0 && x >= 0.0 && y >= 0.0 && x + y <= 100.0
+}
+
+/// Split a history download response into its scan records.
+///
+/// The framing is a guess, as the history command itself is unknown: the response is taken to be
+/// AB44 messages back to back, each ending with its checksum, so `AB44...CRC AB44...CRC ...`.
+/// A record ends at the first checksum that matches, so their length doesn't matter.
+/// Anything after the last record that doesn't look like one (e.g. a short tail) is skipped.
+pub fn split_scan_records(mut msg: &[u8]) -> impl Iterator<Item = &[u8]> {
+	std::iter::from_fn(move || {
+		if msg.is_empty() {
+			return None;
+		}
+		let end = (message_kind(msg) == Some(MessageKind::Scan)).then(|| {
+			(MessageKind::Scan.min_len()..=msg.len()).find(|&end| is_complete(&msg[..end], MessageKind::Scan))
+		}).flatten();
+		let Some(end) = end else {
+			warn!("Skipping malformed stored scans: {:x?}", msg);
+			return None;
+		};
+		let (rec, rest) = msg.split_at(end);
+		msg = rest;
+		Some(rec)
+	})
+}
+
+/// Joins messages that arrive split over several notifications (when they don't fit the MTU).
+///
+/// A message of a known kind is complete once it's long enough to parse, as not every kind is
+/// known to end with a checksum; the checksum is only checked (and logged) once it is. A batch
+/// (e.g. a history download, which is several scan records in one) has no known length, so
+/// while [Reassembler::batch] is set scan messages are held until [Reassembler::flush].
+///
+/// btleplug doesn't expose the negotiated MTU, so the first split message logs the notification size
+/// instead, which is the MTU minus 3 bytes of ATT header.
+pub struct Reassembler {
+	/// The message so far and when its last fragment arrived
+	partial: Option<(Vec<u8>, Instant)>,
+	/// Fragments further apart than this are not stitched together
+	window: Duration,
+	/// Whether the notification size was logged already
+	reported: bool,
+	/// Messages given up on waiting for (see [Reassembler::flush]) that didn't end with their checksum
+	pub checksum_failures: usize,
+	/// Whether scan messages are part of a batch, which only ends with [Reassembler::flush]
+	pub batch: bool,
+}
+
+impl Reassembler {
+	
+	pub fn new(window: Duration) -> Self {
+		Self { partial: None, window, reported: false, checksum_failures: 0, batch: false }
+	}
+	
+	/// Feed a notification, get back a message once it's complete
+	pub fn push(&mut self, chunk: Vec<u8>) -> Option<Vec<u8>> {
+		let chunk_len = chunk.len();
+		let msg = match self.partial.take() {
+			Some((mut partial, at)) if at.elapsed() <= self.window => {
+				if !self.reported {
+					self.reported = true;
+					info!("Messages are split over notifications of {} bytes, so the MTU is probably {}", partial.len(), partial.len() + 3);
+				}
+				partial.extend_from_slice(&chunk);
+				debug!("Appended fragment, now {} bytes", partial.len());
+				partial
+			},
+			Some((partial, _)) => {
+				warn!("Dropping incomplete message: {:x?}", partial);
+				chunk
+			},
+			None => chunk,
+		};
+		match message_kind(&msg) {
+			Some(kind) if msg.len() < kind.min_len() || (self.batch && kind == MessageKind::Scan) => {
+				debug!("Got {} bytes for {kind:?} (last fragment {chunk_len}), waiting for the rest", msg.len());
+				self.partial = Some((msg, Instant::now()));
+				None
+			},
+			Some(kind) => {
+				self.check(&msg, kind);
+				Some(msg)
+			},
+			None => Some(msg),
+		}
+	}
+	
+	/// When to give up waiting for more of the current message, if there is one
+	pub fn deadline(&self) -> Option<Instant> {
+		self.partial.as_ref().map(|(_, at)| *at + self.window)
+	}
+	
+	/// Stop waiting for more: get the message so far if it's long enough to parse, or drop it
+	pub fn flush(&mut self) -> Option<Vec<u8>> {
+		let (msg, _) = self.partial.take()?;
+		if !ends_with_checksum(&msg) {
+			self.checksum_failures += 1;
+		}
+		match message_kind(&msg) {
+			Some(kind) if msg.len() >= kind.min_len() => {
+				debug!("No more fragments, taking {} bytes for {kind:?} as they are", msg.len());
+				self.check(&msg, kind);
+				Some(msg)
+			},
+			_ => {
+				warn!("Dropping incomplete message: {:x?}", msg);
+				None
+			},
+		}
+	}
+	
+	/// Log a message that doesn't end with its checksum. Not an error, as that's only known for some kinds.
+	fn check(&mut self, msg: &[u8], kind: MessageKind) {
+		if !is_complete(msg, kind) {
+			debug!("{kind:?} message doesn't end with its checksum: {:x?}", msg);
+		}
+	}
+	
+}
+
+/// Long enough to parse, and ends with the checksum of the rest
+fn is_complete(msg: &[u8], kind: MessageKind) -> bool {
+	msg.len() >= kind.min_len() && ends_with_checksum(msg)
+}
+
+fn ends_with_checksum(msg: &[u8]) -> bool {
+	msg.len() >= 2 && {
+		let (body, crc) = msg.split_at(msg.len() - 2);
+		checksum(body).to_le_bytes() == crc
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn known_commands() {
+		assert_eq!(hex::encode_upper(&*SCAN_CMD), "AB440000000036001864");
+		assert_eq!(hex::encode_upper(&*CALIBRATE_CMD), "AB202E000200904F");
+		assert_eq!(hex::encode_upper(&*BATTERY_CMD), "AB200B0002009B43");
+		assert_eq!(hex::encode_upper(&*INFO_CMD), "AB400000000014004504");
+	}
+	
+	#[test]
+	fn reassemble_scan() {
+		let mut r = Reassembler::new(Duration::from_secs(1));
+		let msg = crate::testing::scan_frame();
+		assert_eq!(r.push(msg[..20].to_vec()), None);
+		assert!(r.deadline().is_some());
+		assert_eq!(r.push(msg[20..].to_vec()), Some(msg.clone()));
+		assert_eq!(r.deadline(), None);
+		let calibrated = crate::testing::calibrated_frame();
+		assert_eq!(r.push(calibrated.clone()), Some(calibrated.clone()));
+		
+		// Complete once long enough, so what follows isn't joined to it
+		assert_eq!(r.push(msg.clone()), Some(msg.clone()));
+		assert_eq!(r.push(calibrated.clone()), Some(calibrated));
+		
+		// A batch is up to the caller to end
+		r.batch = true;
+		let batch = [&msg[..], &msg, &[0xAB, 0x44, 0x00]].concat();
+		assert_eq!(r.push(batch[..50].to_vec()), None);
+		assert_eq!(r.push(batch[50..].to_vec()), None);
+		assert_eq!(r.flush(), Some(batch));
+		assert_eq!(r.push(msg[..20].to_vec()), None);
+		assert_eq!(r.flush(), None);
+		assert_eq!(r.deadline(), None);
+		// Neither ended with a checksum
+		assert_eq!(r.checksum_failures, 2);
+		assert_eq!(r.push(msg[..20].to_vec()), None);
+		assert_eq!(r.push(msg[20..].to_vec()), None);
+		assert_eq!(r.flush(), Some(msg));
+		assert_eq!(r.checksum_failures, 2);
+	}
+	
+	#[test]
+	fn scan_layouts() {
+		let msg = crate::testing::scan_frame();
+		assert_eq!(parse_scan_result(&msg, 1).unwrap(), crate::testing::scan_result(1));
+		
+		// Whatever follows the RGB bytes doesn't matter
+		let mut longer = msg.clone();
+		longer.extend_from_slice(&[0; 6]);
+		assert_eq!(parse_scan_result(&longer, 1).unwrap(), crate::testing::scan_result(1));
+		assert!(parse_scan_result(&msg[..39], 1).is_ok());
+		assert!(parse_scan_result(&msg[..38], 1).is_err());
+		
+		// Fields elsewhere make for impossible values
+		let mut shifted = msg[..8].to_vec();
+		shifted.extend_from_slice(&[0xFF, 0x7F]);
+		shifted.extend_from_slice(&msg[8..]);
+		let err = parse_scan_result(&shifted, 1).unwrap_err().to_string();
+		assert!(err.starts_with("Scan message with unknown layout (43 bytes): AB44000000000000FF7F"), "{err}");
+	}
+	
+	#[test]
+	fn scan_records() {
+		let scan = crate::testing::scan_frame();
+		let longer = frame(&[&scan[..39], &[0; 6]].concat());
+		let batch = [&scan[..], &longer, &scan, &[0xAB, 0x44, 0x00]].concat();
+		let records: Vec<&[u8]> = split_scan_records(&batch).collect();
+		assert_eq!(records, vec![&scan[..], &longer, &scan]);
+		assert_eq!(split_scan_records(&[]).count(), 0);
+		assert_eq!(split_scan_records(&scan[..30]).count(), 0);
+	}
+	
+	#[test]
+	fn calibration_response() {
+		let msg = hex::decode("AB202E00020000002DF4").unwrap();
+		assert_eq!(checksum(&msg[..8]).to_le_bytes(), msg[8..]);
+	}
+	
+}
@@ -0,0 +1,161 @@
+use log::warn;
+use tokio::sync::{broadcast, mpsc};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tokio_stream::{Stream, StreamExt};
+use tokio_stream::wrappers::BroadcastStream;
+use crate::device::{device_channel, device_loop, Command, DeviceConfig, DeviceHandle, Event, Transport};
+use crate::output::ScanResult;
+
+/// How many events a slow subscriber can fall behind before it starts missing them
+const EVENT_CAPACITY: usize = 256;
+
+/// The device as a few async methods, for when matching [Event]s by hand is too much.
+///
+/// ```no_run
+/// # async fn example(peripheral: btleplug::platform::Peripheral) -> Result<(), anyhow::Error> {
+/// use bluecolor::{BleTransport, ColorReader, DeviceConfig};
+/// let reader = ColorReader::new(BleTransport::connect(peripheral).await?, DeviceConfig::default());
+/// let result = reader.scan().await?;
+/// println!("{}", result.to_hex());
+/// reader.disconnect().await
+/// # }
+/// ```
+pub struct ColorReader {
+	handle: DeviceHandle,
+	/// Never read, only used to subscribe more receivers
+	events: broadcast::Receiver<Event>,
+	task: JoinHandle<Result<(), anyhow::Error>>,
+}
+
+impl ColorReader {
+	
+	/// Starts a [device_loop] for the transport. Must be called within a Tokio runtime.
+	pub fn new<T: Transport>(transport: T, config: DeviceConfig) -> Self {
+		let (handle, commands) = device_channel();
+		let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+		let (broadcast_tx, events) = broadcast::channel(EVENT_CAPACITY);
+		let task = tokio::spawn(async move {
+			let forward = async move {
+				while let Some(event) = events_rx.recv().await {
+					broadcast_tx.send(event).ok();
+				}
+			};
+			tokio::join!(device_loop(transport, config, commands, events_tx), forward).0
+		});
+		Self { handle, events, task }
+	}
+	
+	/// Every event from now on
+	pub fn events(&self) -> impl Stream<Item = Event> {
+		BroadcastStream::new(self.events.resubscribe()).filter_map(|event| match event {
+			Ok(event) => Some(event),
+			Err(e) => {
+				warn!("Missed events: {e}");
+				None
+			},
+		})
+	}
+	
+	/// Every scan from now on, whoever asked for it (including the device's own button)
+	pub fn scans(&self) -> impl Stream<Item = ScanResult> {
+		self.events().filter_map(|event| match event {
+			Event::Scan(result) if !result.historical => Some(result),
+			_ => None,
+		})
+	}
+	
+	/// Scan and return the result.
+	///
+	/// This is the first live scan after the command is sent, which isn't necessarily its answer:
+	/// the device sends scans from its own button the same way, so if the button is pressed in the
+	/// meantime (or while other commands are still queued ahead of this one) that scan is returned
+	/// instead, and the actual answer only shows up in [ColorReader::scans].
+	pub async fn scan(&self) -> Result<ScanResult, anyhow::Error> {
+		self.request(Command::Scan, |event| match event {
+			Event::Scan(result) if !result.historical => Some(result),
+			_ => None,
+		}).await
+	}
+	
+	pub async fn calibrate(&self) -> Result<(), anyhow::Error> {
+		self.request(Command::Calibrate, |event| match event {
+			Event::Calibrated => Some(()),
+			_ => None,
+		}).await
+	}
+	
+	/// Battery level, as reported by the device
+	pub async fn battery(&self) -> Result<i16, anyhow::Error> {
+		self.request(Command::Status, |event| match event {
+			Event::PowerLevel(level) => Some(level),
+			_ => None,
+		}).await
+	}
+	
+	/// Disconnect and wait for the device loop to finish
+	pub async fn disconnect(self) -> Result<(), anyhow::Error> {
+		self.handle.send(Command::Disconnect)?;
+		self.task.await?
+	}
+	
+	/// Send a command and wait for the first event `pick` accepts
+	async fn request<R>(&self, cmd: Command, pick: impl Fn(Event) -> Option<R>) -> Result<R, anyhow::Error> {
+		// Subscribe first so the answer can't slip by
+		let mut events = self.events.resubscribe();
+		self.handle.send(cmd)?;
+		loop {
+			match events.recv().await {
+				Ok(event) => if let Some(res) = pick(event) { return Ok(res); },
+				Err(RecvError::Lagged(n)) => warn!("Missed {n} events"),
+				Err(RecvError::Closed) => return Err(anyhow::Error::msg("Device connection is closed")),
+			}
+		}
+	}
+	
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::protocol::{BATTERY_CMD, CALIBRATE_CMD, INFO_CMD, SCAN_CMD};
+	use crate::testing::*;
+	use super::*;
+	
+	fn reader() -> ColorReader {
+		let (transport, device) = FakeTransport::new();
+		let transport = transport
+			.replying(&device, &SCAN_CMD, scan_frame())
+			.replying(&device, &CALIBRATE_CMD, calibrated_frame())
+			.replying(&device, &INFO_CMD, info_frame())
+			.replying(&device, &BATTERY_CMD, power_frame());
+		ColorReader::new(transport, DeviceConfig::default())
+	}
+	
+	#[tokio::test]
+	async fn three_scans() {
+		let reader = reader();
+		let scans = reader.scans();
+		
+		let mut results = Vec::new();
+		for _ in 0..3 {
+			results.push(reader.scan().await.unwrap());
+		}
+		assert_eq!(results.iter().map(|r| r.idx).collect::<Vec<_>>(), vec![1, 2, 3]);
+		assert_eq!(ScanResult { id: scan_result(1).id, ..results[0].clone() }, scan_result(1));
+		assert_ne!(results[0].id, results[1].id);
+		
+		let streamed: Vec<ScanResult> = scans.take(3).collect().await;
+		assert_eq!(streamed, results);
+		
+		reader.disconnect().await.unwrap();
+	}
+	
+	#[tokio::test]
+	async fn calibrate_and_battery() {
+		let reader = reader();
+		reader.calibrate().await.unwrap();
+		assert_eq!(reader.battery().await.unwrap(), 41);
+		reader.disconnect().await.unwrap();
+	}
+	
+}
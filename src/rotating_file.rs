@@ -0,0 +1,179 @@
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// A size in bytes, optionally with a K, M or G suffix (powers of 1024), e.g. `10M`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileSize(pub u64);
+
+impl FromStr for FileSize {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (num, unit) = match s.trim().to_ascii_uppercase() {
+			t if t.ends_with('K') => (t[..t.len() - 1].to_string(), 1 << 10),
+			t if t.ends_with('M') => (t[..t.len() - 1].to_string(), 1 << 20),
+			t if t.ends_with('G') => (t[..t.len() - 1].to_string(), 1 << 30),
+			t => (t, 1),
+		};
+		match num.trim().parse::<u64>() {
+			Ok(n) if n > 0 => Ok(Self(n * unit)),
+			_ => Err(format!("Invalid size (expected e.g. 512K or 10M): {s}")),
+		}
+	}
+}
+
+struct Inner {
+	file: File,
+	/// Bytes in the current file
+	size: u64,
+	/// The end of a line still being written
+	partial: Vec<u8>,
+}
+
+/// A file appended to line by line, which is renamed to `FILE.1` (and older ones to `FILE.2` and so on,
+/// up to `keep`) and started anew before it would grow past `max_size`. Lines are never split between files.
+#[derive(Clone)]
+pub struct RotatingFile {
+	path: PathBuf,
+	max_size: u64,
+	keep: usize,
+	inner: Arc<Mutex<Inner>>,
+}
+
+impl RotatingFile {
+	
+	pub fn open(path: impl AsRef<Path>, max_size: u64, keep: usize) -> Result<Self, anyhow::Error> {
+		let path = path.as_ref().to_path_buf();
+		let file = Self::open_file(&path)?;
+		let size = file.metadata()?.len();
+		Ok(Self { path, max_size, keep, inner: Arc::new(Mutex::new(Inner { file, size, partial: Vec::new() })) })
+	}
+	
+	/// Rotate now, e.g. for log shipping, unless nothing was written since the last time.
+	/// Safe to call while another clone is writing: a line in progress goes to the new file.
+	pub fn rotate(&self) -> Result<(), anyhow::Error> {
+		let mut inner = self.inner.lock().unwrap();
+		if inner.size > 0 {
+			self.rotate_locked(&mut inner)?;
+		}
+		Ok(())
+	}
+	
+	fn rotate_locked(&self, inner: &mut Inner) -> Result<(), anyhow::Error> {
+		for n in (1..self.keep).rev() {
+			rename_if_exists(&rotated_path(&self.path, n), &rotated_path(&self.path, n + 1))?;
+		}
+		match self.keep {
+			0 => std::fs::remove_file(&self.path)?,
+			_ => std::fs::rename(&self.path, rotated_path(&self.path, 1))?,
+		}
+		inner.file = Self::open_file(&self.path)?;
+		inner.size = 0;
+		Ok(())
+	}
+	
+	fn open_file(path: &Path) -> Result<File, anyhow::Error> {
+		OpenOptions::new().create(true).append(true).open(path)
+			.map_err(|e| anyhow::Error::msg(format!("Failed to open output file {}: {e}", path.display())))
+	}
+	
+}
+
+/// `FILE.n`
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+	let mut name = path.as_os_str().to_os_string();
+	name.push(format!(".{n}"));
+	name.into()
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> std::io::Result<()> {
+	match std::fs::rename(from, to) {
+		Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+		res => res,
+	}
+}
+
+impl Write for RotatingFile {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let mut inner = self.inner.lock().unwrap();
+		inner.partial.extend_from_slice(buf);
+		let Some(end) = inner.partial.iter().rposition(|&b| b == b'\n') else { return Ok(buf.len()) };
+		let rest = inner.partial.split_off(end + 1);
+		let lines = std::mem::replace(&mut inner.partial, rest);
+		for line in lines.split_inclusive(|&b| b == b'\n') {
+			// A single line longer than the limit still gets a file of its own
+			if inner.size > 0 && inner.size + line.len() as u64 > self.max_size {
+				self.rotate_locked(&mut inner).map_err(std::io::Error::other)?;
+			}
+			inner.file.write_all(line)?;
+			inner.size += line.len() as u64;
+		}
+		Ok(buf.len())
+	}
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.inner.lock().unwrap().file.flush()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn sizes() {
+		assert_eq!("100".parse(), Ok(FileSize(100)));
+		assert_eq!("512k".parse(), Ok(FileSize(512 * 1024)));
+		assert_eq!("10M".parse(), Ok(FileSize(10 * 1024 * 1024)));
+		assert_eq!("1G".parse(), Ok(FileSize(1024 * 1024 * 1024)));
+		assert!("".parse::<FileSize>().is_err());
+		assert!("0".parse::<FileSize>().is_err());
+		assert!("10MB".parse::<FileSize>().is_err());
+	}
+	
+	#[test]
+	fn rotation() {
+		let dir = std::env::temp_dir().join(format!("bluecolor-test-rotate-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("scans.jsonl");
+		let read = |n: usize| std::fs::read_to_string(match n {
+			0 => path.clone(),
+			n => rotated_path(&path, n),
+		}).ok();
+		assert_eq!(rotated_path(&path, 2), dir.join("scans.jsonl.2"));
+		
+		let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+		// Written in pieces, like writeln! does
+		write!(file, "aaaa").unwrap();
+		writeln!(file).unwrap();
+		writeln!(file, "bbbb").unwrap();
+		assert_eq!(read(0).as_deref(), Some("aaaa\nbbbb\n"));
+		
+		// Would make 15 bytes, so the line goes to a new file whole
+		write!(file, "cc").unwrap();
+		writeln!(file, "cc").unwrap();
+		assert_eq!(read(0).as_deref(), Some("cccc\n"));
+		assert_eq!(read(1).as_deref(), Some("aaaa\nbbbb\n"));
+		
+		// Only two kept
+		writeln!(file, "dddddddddddd").unwrap();
+		writeln!(file, "e").unwrap();
+		assert_eq!(read(0).as_deref(), Some("e\n"));
+		assert_eq!(read(1).as_deref(), Some("dddddddddddd\n"));
+		assert_eq!(read(2).as_deref(), Some("cccc\n"));
+		assert_eq!(read(3), None);
+		
+		// On demand, not splitting the line in progress, and not twice in a row
+		write!(file, "f").unwrap();
+		file.rotate().unwrap();
+		file.rotate().unwrap();
+		writeln!(file).unwrap();
+		assert_eq!(read(0).as_deref(), Some("f\n"));
+		assert_eq!(read(1).as_deref(), Some("e\n"));
+		assert_eq!(read(2).as_deref(), Some("dddddddddddd\n"));
+		
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+	
+}
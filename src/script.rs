@@ -0,0 +1,150 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+/// One line of a --script file
+#[derive(Debug)]
+pub enum ScriptStep {
+	Status,
+	Calibrate,
+	Scan,
+	/// Wait for everything sent so far to be answered, then pause
+	Sleep(Duration),
+}
+
+/// Command names with their aliases
+const COMMANDS: &[(&str, &[&str])] = &[
+	("status", &["st"]),
+	("calibrate", &["c", "cal"]),
+	("scan", &["s", "sc"]),
+	("sleep", &[]),
+];
+
+/// Full command name for a name, alias or unambiguous prefix
+fn resolve_command(word: &str) -> Result<&'static str, String> {
+	if let Some((name, _)) = COMMANDS.iter().find(|(name, aliases)| *name == word || aliases.contains(&word)) {
+		return Ok(name);
+	}
+	let matches: Vec<&str> = COMMANDS.iter().map(|(name, _)| *name).filter(|name| name.starts_with(word)).collect();
+	match matches[..] {
+		[name] => Ok(name),
+		[] => {
+			let closest = COMMANDS.iter().map(|(name, _)| (edit_distance(word, name), *name)).min().unwrap();
+			Err(match closest {
+				(dist, name) if dist <= 2 => format!("Unknown command '{word}', did you mean '{name}'?"),
+				_ => format!("Unknown command '{word}'"),
+			})
+		},
+		_ => Err(format!("Ambiguous command '{word}', could be {}", matches.join(", "))),
+	}
+}
+
+/// Levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+	let b: Vec<char> = b.chars().collect();
+	let mut row: Vec<usize> = (0..=b.len()).collect();
+	for (i, ca) in a.chars().enumerate() {
+		let mut prev = row[0];
+		row[0] = i + 1;
+		for (j, cb) in b.iter().enumerate() {
+			let cur = row[j + 1];
+			row[j + 1] = if ca == *cb { prev } else { 1 + prev.min(row[j]).min(cur) };
+			prev = cur;
+		}
+	}
+	row[b.len()]
+}
+
+impl FromStr for ScriptStep {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut words = s.split_whitespace();
+		let cmd = words.next().unwrap_or_default().to_ascii_lowercase();
+		let step = match resolve_command(&cmd)? {
+			"status" => Self::Status,
+			"calibrate" => Self::Calibrate,
+			"scan" => Self::Scan,
+			"sleep" => {
+				let secs = words.next().ok_or("sleep needs a duration in seconds")?;
+				let secs = f32::from_str(secs).ok().filter(|s| s.is_finite() && *s >= 0.0)
+					.ok_or(format!("Invalid sleep duration: {secs}"))?;
+				Self::Sleep(Duration::from_secs_f32(secs))
+			},
+			_ => unreachable!(),
+		};
+		if let Some(extra) = words.next() {
+			return Err(format!("Unexpected argument: {extra}"));
+		}
+		Ok(step)
+	}
+}
+
+/// What to do when a script command fails
+#[derive(Clone, Copy, Debug)]
+pub enum ScriptOnError {
+	Abort,
+	Continue,
+}
+
+impl FromStr for ScriptOnError {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match &*s.to_ascii_lowercase() {
+			"abort" => Ok(Self::Abort),
+			"continue" => Ok(Self::Continue),
+			_ => Err(format!("Unknown error mode: {s}"))
+		}
+	}
+}
+
+/// Parse a script, one command per line. Blank lines and `#` comments are skipped.
+pub fn parse_script(text: &str) -> Result<Vec<ScriptStep>, anyhow::Error> {
+	text.lines().enumerate()
+		.map(|(no, line)| (no + 1, line.split('#').next().unwrap().trim()))
+		.filter(|(_, line)| !line.is_empty())
+		.map(|(no, line)| line.parse().map_err(|e| anyhow::Error::msg(format!("Script line {no}: {e}"))))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn scripts() {
+		let steps = parse_script("# warm up\nstatus\n\n  calibrate  # first\nscan\nsleep 1.5\nSCAN\n").unwrap();
+		assert_eq!(format!("{steps:?}"), "[Status, Calibrate, Scan, Sleep(1.5s), Scan]");
+		assert!(parse_script("").unwrap().is_empty());
+		
+		// Line numbers count the blank and comment lines too
+		let err = |text: &str| parse_script(text).unwrap_err().to_string();
+		assert_eq!(err("status\n# x\n\nsleep\n"), "Script line 4: sleep needs a duration in seconds");
+		assert_eq!(err("sleep -1"), "Script line 1: Invalid sleep duration: -1");
+		assert_eq!(err("sleep soon"), "Script line 1: Invalid sleep duration: soon");
+		assert_eq!(err("scan\nscan twice"), "Script line 2: Unexpected argument: twice");
+		assert_eq!(err("explode"), "Script line 1: Unknown command 'explode'");
+	}
+	
+	#[test]
+	fn command_names() {
+		assert_eq!(resolve_command("scan"), Ok("scan"));
+		assert_eq!(resolve_command("s"), Ok("scan"));
+		assert_eq!(resolve_command("cal"), Ok("calibrate"));
+		assert_eq!(resolve_command("stat"), Ok("status"));
+		assert_eq!(resolve_command("sl"), Ok("sleep"));
+		// Every shorter shared prefix is an alias, so only the empty one is left ambiguous
+		assert_eq!(resolve_command(""), Err("Ambiguous command '', could be status, calibrate, scan, sleep".to_string()));
+		assert_eq!(resolve_command("clibrate"), Err("Unknown command 'clibrate', did you mean 'calibrate'?".to_string()));
+		assert_eq!(resolve_command("scna"), Err("Unknown command 'scna', did you mean 'scan'?".to_string()));
+		assert_eq!(resolve_command("slumber"), Err("Unknown command 'slumber'".to_string()));
+	}
+	
+	#[test]
+	fn edit_distances() {
+		assert_eq!(edit_distance("scan", "scan"), 0);
+		assert_eq!(edit_distance("clibrate", "calibrate"), 1);
+		assert_eq!(edit_distance("scna", "scan"), 2);
+		assert_eq!(edit_distance("", "sleep"), 5);
+		assert_eq!(edit_distance("slumber", "sleep"), 4);
+	}
+	
+}
@@ -0,0 +1,133 @@
+//! Fakes and sample data shared by the tests
+
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use uuid::Uuid;
+use crate::device::{CharacteristicLost, NotificationStream, Transport};
+use crate::output::{ScanResult, Triple};
+use crate::protocol::frame;
+
+/// A device that only exists in memory. Notifications are whatever the test sends.
+pub struct FakeTransport {
+	pub written: Arc<Mutex<Vec<Vec<u8>>>>,
+	notifications: Mutex<Option<mpsc::UnboundedReceiver<Vec<u8>>>>,
+	/// Writes that fail before the first one goes through
+	failures: Mutex<usize>,
+	/// Every write fails with [CharacteristicLost]
+	lost: bool,
+	/// Commands answered automatically, and where the answers go
+	replies: Vec<(Vec<u8>, Vec<u8>)>,
+	reply_tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+impl FakeTransport {
+	
+	/// A transport and the sending end of its notifications
+	pub fn new() -> (Self, mpsc::UnboundedSender<Vec<u8>>) {
+		let (device, rx) = mpsc::unbounded_channel();
+		let transport = Self {
+			written: Arc::new(Mutex::new(Vec::new())),
+			notifications: Mutex::new(Some(rx)),
+			failures: Mutex::new(0),
+			lost: false,
+			replies: Vec::new(),
+			reply_tx: None,
+		};
+		(transport, device)
+	}
+	
+	/// Make the first `n` writes fail
+	pub fn failing(self, n: usize) -> Self {
+		Self { failures: Mutex::new(n), ..self }
+	}
+	
+	/// Make every write fail as if the write characteristic were gone
+	pub fn lost(self) -> Self {
+		Self { lost: true, ..self }
+	}
+	
+	/// Answer every write of `cmd` with `reply`. Keeps the notifications open until disconnected.
+	pub fn replying(mut self, device: &mpsc::UnboundedSender<Vec<u8>>, cmd: &[u8], reply: Vec<u8>) -> Self {
+		self.replies.push((cmd.to_vec(), reply));
+		self.reply_tx = Some(device.clone());
+		self
+	}
+	
+}
+
+impl Transport for FakeTransport {
+	async fn subscribe(&self) -> Result<(), anyhow::Error> {
+		Ok(())
+	}
+	async fn notifications(&self) -> Result<NotificationStream, anyhow::Error> {
+		let rx = self.notifications.lock().unwrap().take().expect("notifications taken twice");
+		Ok(Box::pin(UnboundedReceiverStream::new(rx)))
+	}
+	async fn write(&self, data: &[u8]) -> Result<(), anyhow::Error> {
+		if self.lost {
+			return Err(CharacteristicLost.into());
+		}
+		{
+			let mut failures = self.failures.lock().unwrap();
+			if *failures > 0 {
+				*failures -= 1;
+				return Err(anyhow::Error::msg("write failed"));
+			}
+		}
+		self.written.lock().unwrap().push(data.to_vec());
+		if let (Some(tx), Some((_, reply))) = (&self.reply_tx, self.replies.iter().find(|(cmd, _)| cmd == data)) {
+			tx.send(reply.clone()).ok();
+		}
+		Ok(())
+	}
+	async fn disconnect(&self) -> Result<(), anyhow::Error> {
+		Ok(())
+	}
+}
+
+/// The scan from the README's text example, as a made-up message rather than a capture: only the
+/// fields the parser reads and the checksum are meaningful, the other header and CMYK bytes are placeholders
+pub fn scan_frame() -> Vec<u8> {
+	let mut body = vec![0xAB, 0x44, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+	for n in [92.58, -0.27, 0.54, 92.58, -0.04, 0.87, 92.58, 0.6, 116.51, 82.03, 31.44, 33.21] {
+		body.extend_from_slice(&(((n as f32) * 100.0).round() as i16).to_le_bytes());
+	}
+	body.extend_from_slice(&[0x00, 0x00, 0x01, 0x08]);
+	body.extend_from_slice(&[234, 234, 231]);
+	frame(&body)
+}
+
+/// What [scan_frame] parses to
+pub fn scan_result(idx: usize) -> ScanResult {
+	ScanResult {
+		id: Uuid::nil(),
+		idx,
+		lab: Triple([92.58, -0.27, 0.54]),
+		luv: Triple([92.58, -0.04, 0.87]),
+		lch: Triple([92.58, 0.6, 116.51]),
+		yxy: Triple([82.03, 31.44, 33.21]),
+		rgb: Triple([234, 234, 231]),
+		rate: None,
+		historical: false,
+		label: None,
+		location: None,
+		raw: None,
+	}
+}
+
+pub fn calibrated_frame() -> Vec<u8> {
+	hex::decode("AB202E00020000002DF4").unwrap()
+}
+
+/// Power level 41
+pub fn power_frame() -> Vec<u8> {
+	frame(&[0xAB, 0x20, 0x0B, 0x00, 0x02, 0x00, 0x29, 0x00])
+}
+
+/// Device info with bytes 0 to 15 as the payload
+pub fn info_frame() -> Vec<u8> {
+	let mut body = vec![0xAB, 0x40, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00];
+	body.extend((0..16).map(|n| n as u8));
+	frame(&body)
+}
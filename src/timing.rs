@@ -0,0 +1,29 @@
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+use log::warn;
+
+/// CSV trace of BLE traffic: `timestamp_ns,direction,bytes_hex`, timestamps relative to creation
+pub struct TimingLog {
+	start: Instant,
+	file: Mutex<File>,
+}
+
+impl TimingLog {
+	
+	pub fn create(path: &str) -> Result<Self, anyhow::Error> {
+		let mut file = File::create(path)?;
+		file.write_all(b"timestamp_ns,direction,bytes_hex\n")?;
+		Ok(Self { start: Instant::now(), file: Mutex::new(file) })
+	}
+	
+	/// Direction is `write` or `notify`. Written line by line so the trace survives a Ctrl-C.
+	pub fn record(&self, direction: &str, bytes: &[u8]) {
+		let line = format!("{},{},{}\n", self.start.elapsed().as_nanos(), direction, hex::encode(bytes));
+		if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+			warn!("Failed to write timing log: {e}");
+		}
+	}
+	
+}
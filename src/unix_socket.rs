@@ -0,0 +1,135 @@
+//! Output for local clients over a Unix domain socket, one JSON line per event
+
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use log::{debug, info, warn};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixListener;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Lines a slow client can fall behind before it starts missing them
+const CLIENT_CAPACITY: usize = 256;
+
+/// Removes the socket file when dropped
+pub struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+	fn drop(&mut self) {
+		if let Err(e) = std::fs::remove_file(&self.0) {
+			warn!("Failed to remove socket {}: {e}", self.0.display());
+		}
+	}
+}
+
+/// Listen at `path`, replacing a socket left over from an earlier run (but not any other file)
+pub fn bind(path: &str) -> Result<(UnixListener, SocketFile), anyhow::Error> {
+	match std::fs::symlink_metadata(path) {
+		Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+		Ok(_) => return Err(anyhow::Error::msg(format!("{path} exists and is not a socket"))),
+		Err(_) => {},
+	}
+	let listener = UnixListener::bind(path)
+		.map_err(|e| anyhow::Error::msg(format!("Failed to listen on {path}: {e}")))?;
+	info!("Listening on {path}");
+	Ok((listener, SocketFile(path.into())))
+}
+
+/// Sends every complete line written to it to all connected clients
+pub struct Broadcaster {
+	tx: broadcast::Sender<Vec<u8>>,
+	partial: Vec<u8>,
+}
+
+impl Broadcaster {
+	
+	pub fn new() -> Self {
+		Self { tx: broadcast::channel(CLIENT_CAPACITY).0, partial: Vec::new() }
+	}
+	
+	/// For [serve]
+	pub fn sender(&self) -> broadcast::Sender<Vec<u8>> {
+		self.tx.clone()
+	}
+	
+}
+
+impl Default for Broadcaster {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Write for Broadcaster {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.partial.extend_from_slice(buf);
+		// Whole lines only, so a client that falls behind never gets half of one
+		if let Some(end) = self.partial.iter().rposition(|&b| b == b'\n') {
+			let rest = self.partial.split_off(end + 1);
+			// Nobody connected is fine
+			self.tx.send(std::mem::replace(&mut self.partial, rest)).ok();
+		}
+		Ok(buf.len())
+	}
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+
+/// Accept clients until the listener fails, sending each one the lines from then on
+pub async fn serve(listener: UnixListener, lines: broadcast::Sender<Vec<u8>>) -> Result<(), anyhow::Error> {
+	loop {
+		let (mut stream, _) = listener.accept().await?;
+		let mut lines = lines.subscribe();
+		debug!("Socket client connected");
+		tokio::spawn(async move {
+			loop {
+				let data = match lines.recv().await {
+					Ok(data) => data,
+					Err(RecvError::Lagged(n)) => {
+						warn!("Socket client missed {n} line(s)");
+						continue;
+					},
+					Err(RecvError::Closed) => break,
+				};
+				if stream.write_all(&data).await.is_err() { break; }
+			}
+			debug!("Socket client gone");
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+	use tokio::io::{AsyncBufReadExt, BufReader};
+	use tokio::net::UnixStream;
+	use super::*;
+	
+	#[tokio::test]
+	async fn lines_reach_clients() {
+		let path = std::env::temp_dir().join(format!("bluecolor-test-{}.sock", std::process::id()));
+		let path = path.to_str().unwrap();
+		let (listener, file) = bind(path).unwrap();
+		let mut out = Broadcaster::new();
+		tokio::spawn(serve(listener, out.sender()));
+		
+		let mut client = BufReader::new(UnixStream::connect(path).await.unwrap());
+		// Lines written before the server subscribes the client don't reach it
+		let sender = out.sender();
+		tokio::time::timeout(Duration::from_secs(1), async {
+			while sender.receiver_count() == 0 { tokio::time::sleep(Duration::from_millis(1)).await; }
+		}).await.expect("client never subscribed");
+		write!(out, "{{\"power_level\":41}}").unwrap();
+		writeln!(out).unwrap();
+		
+		let mut line = String::new();
+		tokio::time::timeout(Duration::from_secs(1), client.read_line(&mut line)).await.expect("no line").unwrap();
+		assert_eq!(line, "{\"power_level\":41}\n");
+		
+		drop(file);
+		assert!(std::fs::metadata(path).is_err());
+	}
+	
+}
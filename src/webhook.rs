@@ -0,0 +1,176 @@
+//! A minimal HTTP endpoint for triggering scans from other systems
+
+use std::time::Duration;
+use log::{debug, info, warn};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Take};
+use tokio::net::TcpListener;
+use crate::device::{Command, DeviceHandle};
+
+/// Requests with a bigger body are refused
+const MAX_BODY: usize = 64 * 1024;
+
+/// Requests with a longer request line and headers are refused
+const MAX_HEADER: u64 = 8 * 1024;
+
+/// Clients that take longer than this to send the request are dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq)]
+struct Request {
+	method: String,
+	path: String,
+	body: Vec<u8>,
+}
+
+/// The request line and headers go over [MAX_HEADER]
+#[derive(Debug)]
+struct HeaderTooLarge;
+
+impl std::fmt::Display for HeaderTooLarge {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "Request headers too large")
+	}
+}
+
+impl std::error::Error for HeaderTooLarge {}
+
+/// Listen on one interface (or all of them with `0.0.0.0`). There's no authentication, so anyone
+/// who can reach the port can trigger scans.
+pub async fn bind(host: &str, port: u16) -> Result<TcpListener, anyhow::Error> {
+	let listener = TcpListener::bind((host, port)).await
+		.map_err(|e| anyhow::Error::msg(format!("Failed to listen on {host}:{port}: {e}")))?;
+	info!("Listening for webhooks on {host}:{port}");
+	Ok(listener)
+}
+
+/// Answer requests until the listener fails:
+/// - `POST /trigger` scans, labelling the result with `label` from an optional `{"label":"..."}` body
+/// - `GET /health` is 200 while the device is connected, 503 after
+pub async fn serve(listener: TcpListener, handle: DeviceHandle) -> Result<(), anyhow::Error> {
+	loop {
+		let (mut stream, addr) = listener.accept().await?;
+		let handle = handle.clone();
+		tokio::spawn(async move {
+			let (read, mut write) = stream.split();
+			let req = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut BufReader::new(read))).await
+				.unwrap_or_else(|_| Err(anyhow::Error::msg("Timed out")));
+			let (status, text) = match req {
+				Ok(req) => {
+					debug!("Webhook from {addr}: {} {}", req.method, req.path);
+					respond(&req, &handle)
+				},
+				Err(e) => {
+					warn!("Bad webhook request from {addr}: {e}");
+					match e.is::<HeaderTooLarge>() {
+						true => ("431 Request Header Fields Too Large", e.to_string()),
+						false => ("400 Bad Request", e.to_string()),
+					}
+				},
+			};
+			let response = format!(
+				"HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{text}",
+				text.len(),
+			);
+			if let Err(e) = write.write_all(response.as_bytes()).await {
+				warn!("Failed to answer webhook from {addr}: {e}");
+			}
+		});
+	}
+}
+
+fn respond(req: &Request, handle: &DeviceHandle) -> (&'static str, String) {
+	match (req.method.as_str(), req.path.as_str()) {
+		("POST", "/trigger") => {
+			let label = match parse_label(&req.body) {
+				Ok(label) => label,
+				Err(e) => return ("400 Bad Request", e.to_string()),
+			};
+			let cmd = match label {
+				Some(label) => Command::ScanLabeled(label),
+				None => Command::Scan,
+			};
+			match handle.send(cmd) {
+				Ok(()) => ("200 OK", "OK".to_string()),
+				Err(e) => ("503 Service Unavailable", e.to_string()),
+			}
+		},
+		("GET", "/health") if handle.is_closed() => ("503 Service Unavailable", "Disconnected".to_string()),
+		("GET", "/health") => ("200 OK", "Connected".to_string()),
+		(_, "/trigger" | "/health") => ("405 Method Not Allowed", "Method not allowed".to_string()),
+		_ => ("404 Not Found", "Not found".to_string()),
+	}
+}
+
+/// An empty body is fine, otherwise it must be a JSON object
+fn parse_label(body: &[u8]) -> Result<Option<String>, anyhow::Error> {
+	let text = std::str::from_utf8(body)?.trim();
+	if text.is_empty() { return Ok(None); }
+	let json = jzon::parse(text)?;
+	if !json.is_object() {
+		return Err(anyhow::Error::msg("Expected a JSON object"));
+	}
+	Ok(json["label"].as_str().map(str::to_string))
+}
+
+async fn read_request(read: &mut (impl AsyncBufRead + Unpin)) -> Result<Request, anyhow::Error> {
+	let mut head = (&mut *read).take(MAX_HEADER);
+	let mut line = String::new();
+	read_header_line(&mut head, &mut line).await?;
+	let mut parts = line.split_whitespace();
+	let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+		return Err(anyhow::Error::msg("Malformed request line"));
+	};
+	let (method, path) = (method.to_string(), path.to_string());
+	
+	let mut length = 0;
+	loop {
+		line.clear();
+		if read_header_line(&mut head, &mut line).await? == 0 { break; }
+		let header = line.trim_end();
+		if header.is_empty() { break; }
+		if let Some((name, value)) = header.split_once(':') {
+			if name.eq_ignore_ascii_case("content-length") {
+				length = value.trim().parse()?;
+			}
+		}
+	}
+	if length > MAX_BODY {
+		return Err(anyhow::Error::msg("Request body too large"));
+	}
+	
+	let mut body = vec![0; length];
+	read.read_exact(&mut body).await?;
+	Ok(Request { method, path, body })
+}
+
+/// A line of the request head, which is cut off at [MAX_HEADER] in total
+async fn read_header_line(head: &mut Take<impl AsyncBufRead + Unpin>, line: &mut String) -> Result<usize, anyhow::Error> {
+	let len = head.read_line(line).await?;
+	if head.limit() == 0 && !line.ends_with('\n') {
+		return Err(HeaderTooLarge.into());
+	}
+	Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[tokio::test]
+	async fn requests() {
+		let raw = b"POST /trigger HTTP/1.1\r\nHost: x\r\nContent-Length: 22\r\n\r\n{\"label\":\"sample-001\"}";
+		let req = read_request(&mut &raw[..]).await.unwrap();
+		assert_eq!(req, Request { method: "POST".into(), path: "/trigger".into(), body: raw[raw.len() - 22..].to_vec() });
+		assert_eq!(parse_label(&req.body).unwrap(), Some("sample-001".to_string()));
+		
+		let req = read_request(&mut &b"GET /health HTTP/1.1\r\n\r\n"[..]).await.unwrap();
+		assert_eq!(req.body, b"");
+		assert_eq!(parse_label(&req.body).unwrap(), None);
+		
+		assert!(read_request(&mut &b"\r\n"[..]).await.is_err());
+		let huge = [&b"GET /health HTTP/1.1\r\nX-Padding: "[..], &[b'x'; MAX_HEADER as usize], b"\r\n\r\n"].concat();
+		assert!(read_request(&mut &huge[..]).await.unwrap_err().is::<HeaderTooLarge>());
+		assert!(parse_label(b"[1]").is_err());
+	}
+	
+}
@@ -0,0 +1,72 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use log::{error, warn};
+
+/// Lines waiting to be written before the oldest ones start getting dropped
+const CAPACITY: usize = 1024;
+
+#[derive(Default)]
+struct Queue {
+	lines: VecDeque<String>,
+	dropped: usize,
+	closed: bool,
+}
+
+/// Writes output on a dedicated thread, so a stalled stdout (e.g. a paused pipe reader)
+/// can't block notification handling. If the queue fills up, the oldest lines are dropped.
+pub struct LineWriter {
+	queue: Arc<(Mutex<Queue>, Condvar)>,
+	thread: Option<JoinHandle<()>>,
+}
+
+impl LineWriter {
+	
+	pub fn spawn<W: Write + Send + 'static>(mut out: W) -> Self {
+		let queue = Arc::new((Mutex::new(Queue::default()), Condvar::new()));
+		let thread_queue = queue.clone();
+		let thread = std::thread::spawn(move || {
+			let (lock, cond) = &*thread_queue;
+			loop {
+				let (lines, dropped) = {
+					let mut queue = cond.wait_while(lock.lock().unwrap(), |q| q.lines.is_empty() && !q.closed).unwrap();
+					if queue.lines.is_empty() { break; }
+					(std::mem::take(&mut queue.lines), std::mem::take(&mut queue.dropped))
+				};
+				if dropped > 0 {
+					warn!("Output is not keeping up, dropped {dropped} line(s)");
+				}
+				let res = lines.iter().try_for_each(|line| writeln!(out, "{line}")).and_then(|_| out.flush());
+				if let Err(e) = res {
+					error!("Failed to write output: {e}");
+				}
+			}
+		});
+		Self { queue, thread: Some(thread) }
+	}
+	
+	pub fn write(&self, text: String) {
+		let (lock, cond) = &*self.queue;
+		let mut queue = lock.lock().unwrap();
+		if queue.lines.len() >= CAPACITY {
+			queue.lines.pop_front();
+			queue.dropped += 1;
+		}
+		queue.lines.push_back(text);
+		cond.notify_one();
+	}
+	
+}
+
+impl Drop for LineWriter {
+	/// Flushes whatever is still queued
+	fn drop(&mut self) {
+		let (lock, cond) = &*self.queue;
+		lock.lock().unwrap().closed = true;
+		cond.notify_one();
+		if let Some(thread) = self.thread.take() {
+			thread.join().ok();
+		}
+	}
+}